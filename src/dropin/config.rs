@@ -1,4 +1,6 @@
-#[derive(Copy, Clone)]
+use std::path::{Path, PathBuf};
+
+#[derive(Clone)]
 pub enum OpenPolicy {
     OnDisk,
     /// When the file is closed, all contents are lost and
@@ -9,27 +11,41 @@ pub enum OpenPolicy {
     /// InMemory files can be manually added to a backpack
     InMemory,
 
-    /// Creates a new backpack for every thread. Files are created
-    /// in the thread-local backpack they were first opened in. However,
-    /// Files are safe to send to other threads.
-    ThreadLocalBackpack
+    /// Creates a new backpack for every scope, which is released when
+    /// the scope ends. Files are created in the thread-local backpack
+    /// they were first opened in. However, Files are safe to send to
+    /// other threads, and keep their backpack alive.
+    ThreadLocalBackpack,
+
+    /// Reads files from disk, but writes all files into the thread-local
+    /// backpack, so the disk is never changed. Files which were written are
+    /// read from the backpack afterwards, and removed files are hidden.
+    Overlay,
+
+    /// Like [`ThreadLocalBackpack`](OpenPolicy::ThreadLocalBackpack), but all
+    /// threads using the same name share one backpack for the whole process,
+    /// until it's released with [`release_shared`](crate::dropin::release_shared).
+    Shared(String),
 }
 
 #[derive(Clone)]
 pub struct Config {
-    pub open_policy: OpenPolicy
+    pub open_policy: OpenPolicy,
+    /// Where the backpack of a scope is saved when the scope ends, see [`persist_to`](Config::persist_to)
+    pub persist_to: Option<PathBuf>,
 }
 
 impl AsRef<Config> for Config {
     fn as_ref(&self) -> &Config {
-        &self
+        self
     }
 }
 
 impl Config {
     pub fn thread_local() -> Config {
         Self {
-            open_policy: OpenPolicy::ThreadLocalBackpack
+            open_policy: OpenPolicy::ThreadLocalBackpack,
+            persist_to: None,
         }
     }
 
@@ -43,16 +59,48 @@ impl Config {
         self
     }
 
+    pub fn overlay() -> Config {
+        Self {
+            open_policy: OpenPolicy::Overlay,
+            persist_to: None,
+        }
+    }
+
+    pub fn create_overlay(&mut self) -> &mut Self {
+        self.open_policy = OpenPolicy::Overlay;
+        self
+    }
+
+    pub fn shared(name: impl Into<String>) -> Config {
+        Self {
+            open_policy: OpenPolicy::Shared(name.into()),
+            persist_to: None,
+        }
+    }
+
+    pub fn create_shared(&mut self, name: impl Into<String>) -> &mut Self {
+        self.open_policy = OpenPolicy::Shared(name.into());
+        self
+    }
+
     pub fn create_on_disk(&mut self) -> &mut Self {
         self.open_policy = OpenPolicy::OnDisk;
         self
     }
+
+    /// Save the backpack files were written to into a pack at `path` when the
+    /// scope ends, for example to archive what the code under test wrote.
+    pub fn persist_to(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.persist_to = Some(path.as_ref().to_path_buf());
+        self
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            open_policy: OpenPolicy::OnDisk
+            open_policy: OpenPolicy::OnDisk,
+            persist_to: None,
         }
     }
 }
\ No newline at end of file
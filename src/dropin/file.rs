@@ -1,32 +1,77 @@
-use std::fs::Metadata;
 use std::io::{Read, Seek, SeekFrom, Write, Result, Error as IoError};
+use std::mem::ManuallyDrop;
 use std::path::Path;
 use crate::dropin::config::OpenPolicy;
-use crate::{InMemoryFile, pack};
-use crate::dropin::scope::{get_backpack, with_config};
+use crate::dropin::OpenOptions;
+use crate::{BackPack, InMemoryFile, pack};
+use crate::pack::EntryMetadata;
+use crate::dropin::scope::{with_config, SharedBackPack};
 
 pub struct File<'f, 'backpack> {
-    inner: pack::RawFile<'f, 'backpack>,
+    /// Dropped explicitly before `backpack`, since it may borrow from it
+    inner: ManuallyDrop<pack::RawFile<'f, 'backpack>>,
+    /// Whether writes have to be moved to the end of the file,
+    /// for appending files which aren't on disk
+    append: bool,
+    /// Keeps the backpack `inner` is stored in alive
+    backpack: Option<SharedBackPack>,
+}
+
+impl<'f, 'backpack> File<'f, 'backpack> {
+    pub(crate) fn new(inner: pack::RawFile<'f, 'backpack>, append: bool) -> Self {
+        Self {
+            inner: ManuallyDrop::new(inner),
+            append,
+            backpack: None,
+        }
+    }
+}
+
+impl File<'_, 'static> {
+    /// Opens a file stored in `backpack` with `open`. The file keeps the backpack alive.
+    pub(crate) fn in_backpack(
+        backpack: SharedBackPack,
+        append: bool,
+        open: impl for<'a> FnOnce(&'a BackPack<'static, 'static>) -> pack::Result<InMemoryFile<'a, 'static>>,
+    ) -> Result<Self> {
+        let inner = open(&backpack).map_err(Into::<IoError>::into)?;
+        // SAFETY: `open` can only keep the reference to the backpack in the file it returns,
+        // which may then live as long as the backpack does. The backpack is moved into the
+        // file with it, and the file is dropped first, see the `Drop` implementation.
+        let inner = unsafe { std::mem::transmute::<InMemoryFile<'_, 'static>, InMemoryFile<'static, 'static>>(inner) };
+        Ok(Self {
+            inner: ManuallyDrop::new(inner.into()),
+            append,
+            backpack: Some(backpack),
+        })
+    }
+}
+
+impl Drop for File<'_, '_> {
+    fn drop(&mut self) {
+        // SAFETY: `inner` isn't used after this. The backpack it may borrow from is dropped
+        // afterwards, with the other fields.
+        unsafe { ManuallyDrop::drop(&mut self.inner) }
+    }
 }
 
 impl<'f> File<'f, 'static> {
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
         with_config(|config| {
             match config.open_policy {
-                OpenPolicy::OnDisk => Ok(Self {
-                    inner: std::fs::File::create(path)?.into()
-                }),
-                OpenPolicy::InMemory => Ok(Self {
-                    inner: InMemoryFile::new(path).into()
-                }),
-                OpenPolicy::ThreadLocalBackpack => {
-                    let bp = get_backpack();
-                    Ok(Self {
-                        inner: bp.add_empty_file(path)
-                            .map_err(Into::<IoError>::into)?
-                            .into()
-                    })
-                }
+                OpenPolicy::OnDisk => Ok(Self::new(
+                    std::fs::File::create(path)?.into(), false
+                )),
+                OpenPolicy::InMemory => Ok(Self::new(
+                    InMemoryFile::new(path).into(), false
+                )),
+                OpenPolicy::ThreadLocalBackpack |
+                OpenPolicy::Shared(_) |
+                OpenPolicy::Overlay => OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path),
             }
         })
     }
@@ -34,20 +79,17 @@ impl<'f> File<'f, 'static> {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         with_config(|config| {
             match config.open_policy {
-                OpenPolicy::OnDisk => Ok(Self {
-                    inner: std::fs::File::open(path)?.into()
-                }),
-                OpenPolicy::InMemory => Ok(Self {
-                    inner: InMemoryFile::new(path).into()
-                }),
-                OpenPolicy::ThreadLocalBackpack => {
-                    let bp = get_backpack();
-                    Ok(Self {
-                        inner: bp.get_file(path)
-                            .map_err(Into::<IoError>::into)?
-                            .into()
-                    })
-                }
+                OpenPolicy::OnDisk => Ok(Self::new(
+                    std::fs::File::open(path)?.into(), false
+                )),
+                OpenPolicy::InMemory => Ok(Self::new(
+                    InMemoryFile::new(path).into(), false
+                )),
+                OpenPolicy::ThreadLocalBackpack |
+                OpenPolicy::Shared(_) |
+                OpenPolicy::Overlay => OpenOptions::new()
+                    .read(true)
+                    .open(path),
             }
         })
     }
@@ -64,14 +106,16 @@ impl<'f> File<'f, 'static> {
         self.inner.set_len(size).map_err(Into::<IoError>::into)
     }
 
-    pub fn metadata(&self) -> Result<Metadata> {
+    pub fn metadata(&self) -> Result<EntryMetadata> {
         self.inner.metadata().map_err(Into::<IoError>::into)
     }
 
     pub fn try_clone(&self) -> Result<Self> {
         let ri = self.inner.try_clone().map_err(Into::<IoError>::into)?;
         Ok(Self {
-            inner: ri,
+            inner: ManuallyDrop::new(ri),
+            append: self.append,
+            backpack: self.backpack.clone(),
         })
     }
 }
@@ -84,6 +128,9 @@ impl Read for File<'_, '_> {
 
 impl Write for File<'_, '_> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.append {
+            self.inner.seek(SeekFrom::End(0))?;
+        }
         self.inner.write(buf)
     }
 
@@ -98,4 +145,5 @@ impl Seek for File<'_, '_> {
     }
 }
 
-
+#[cfg(feature = "async")]
+crate::pack::async_io::impl_async_io!(File<'_, '_>);
@@ -0,0 +1,133 @@
+//! Drop-in replacements for the free functions of [`std::fs`], which honour the
+//! [`OpenPolicy`] of the current [`Config`](crate::dropin::Config) like
+//! [`File::create`] and [`File::open`] do. Swapping `use std::fs` for
+//! `use backpack::dropin::fs` is enough to port code using them.
+//!
+//! Files which only live in memory ([`OpenPolicy::InMemory`]) can't be found by
+//! name after they were closed, so functions looking them up fail with [`ErrorKind::NotFound`].
+
+use std::io::{Error as IoError, ErrorKind, Read, Result, Write};
+use std::path::Path;
+use crate::dropin::config::OpenPolicy;
+use crate::dropin::overlay;
+use crate::dropin::scope::{get_backpack, with_config};
+use crate::pack::EntryMetadata;
+
+pub use crate::dropin::{File, OpenOptions};
+
+/// Files which only live in memory can't be found by name
+fn not_found(path: &Path) -> IoError {
+    IoError::new(ErrorKind::NotFound, format!("in-memory file {:?} can't be opened by name", path))
+}
+
+/// Opens the file at `path` to read it, like [`File::open`], which would
+/// create an empty file for files which only live in memory
+fn open_existing(path: &Path) -> Result<File<'static, 'static>> {
+    if with_config(|config| matches!(config.open_policy, OpenPolicy::InMemory)) {
+        return Err(not_found(path));
+    }
+    File::open(path)
+}
+
+/// Drop-in replacement for [`std::fs::read`]
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    open_existing(path.as_ref())?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Drop-in replacement for [`std::fs::read_to_string`]
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String> {
+    let mut contents = String::new();
+    open_existing(path.as_ref())?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Drop-in replacement for [`std::fs::write`]
+pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<()> {
+    File::create(path)?.write_all(contents.as_ref())
+}
+
+/// Drop-in replacement for [`std::fs::remove_file`]
+pub fn remove_file<P: AsRef<Path>>(path: P) -> Result<()> {
+    with_config(|config| {
+        match config.open_policy {
+            OpenPolicy::OnDisk => std::fs::remove_file(path),
+            OpenPolicy::InMemory => Err(not_found(path.as_ref())),
+            OpenPolicy::Overlay => overlay::remove_file(path),
+            OpenPolicy::ThreadLocalBackpack | OpenPolicy::Shared(_) => {
+                get_backpack().remove_file(path).map_err(Into::<IoError>::into)
+            }
+        }
+    })
+}
+
+/// Drop-in replacement for [`std::fs::rename`]. In the thread-local backpack, the
+/// contents of the file aren't copied. Unlike [`std::fs::rename`], this fails with
+/// [`ErrorKind::AlreadyExists`] instead of replacing a file at `to` when files
+/// aren't stored on disk.
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    with_config(|config| {
+        match config.open_policy {
+            OpenPolicy::OnDisk => std::fs::rename(from, to),
+            OpenPolicy::InMemory => Err(not_found(from.as_ref())),
+            OpenPolicy::Overlay => overlay::rename(from, to),
+            OpenPolicy::ThreadLocalBackpack | OpenPolicy::Shared(_) => {
+                get_backpack().rename(from, to).map_err(Into::<IoError>::into)
+            }
+        }
+    })
+}
+
+/// Drop-in replacement for [`std::fs::copy`], returning the number of bytes copied.
+/// In the thread-local backpack, both files share their contents until either is
+/// written to. Like [`rename`], this fails with [`ErrorKind::AlreadyExists`] instead
+/// of replacing a file at `to` when files aren't stored on disk.
+pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<u64> {
+    with_config(|config| {
+        match config.open_policy {
+            OpenPolicy::OnDisk => std::fs::copy(from, to),
+            OpenPolicy::InMemory => Err(not_found(from.as_ref())),
+            OpenPolicy::Overlay => overlay::copy(from, to),
+            OpenPolicy::ThreadLocalBackpack | OpenPolicy::Shared(_) => {
+                let bp = get_backpack();
+                bp.copy(from, &to).map_err(Into::<IoError>::into)?;
+                Ok(bp.metadata(to).map_err(Into::<IoError>::into)?.len())
+            }
+        }
+    })
+}
+
+/// Drop-in replacement for [`std::fs::metadata`]
+pub fn metadata<P: AsRef<Path>>(path: P) -> Result<EntryMetadata> {
+    with_config(|config| {
+        match config.open_policy {
+            OpenPolicy::OnDisk => Ok((&std::fs::metadata(path)?).into()),
+            OpenPolicy::InMemory => Err(not_found(path.as_ref())),
+            OpenPolicy::Overlay => overlay::metadata(path),
+            OpenPolicy::ThreadLocalBackpack | OpenPolicy::Shared(_) => {
+                get_backpack().metadata(path).map_err(Into::<IoError>::into)
+            }
+        }
+    })
+}
+
+/// Drop-in replacement for [`std::fs::create_dir_all`]. Files which only
+/// live in memory don't have directories, so this does nothing for them.
+pub fn create_dir_all<P: AsRef<Path>>(path: P) -> Result<()> {
+    with_config(|config| {
+        match config.open_policy {
+            OpenPolicy::OnDisk => std::fs::create_dir_all(path),
+            OpenPolicy::InMemory => Ok(()),
+            OpenPolicy::Overlay => overlay::create_dir_all(path),
+            OpenPolicy::ThreadLocalBackpack | OpenPolicy::Shared(_) => {
+                get_backpack().create_dir_all(path).map_err(Into::<IoError>::into)
+            }
+        }
+    })
+}
+
+/// Drop-in replacement for [`Path::exists`]: whether a file or directory exists at `path`.
+pub fn exists<P: AsRef<Path>>(path: P) -> bool {
+    metadata(path).is_ok()
+}
@@ -1,16 +1,24 @@
 mod scope;
 mod config;
 mod file;
+pub mod fs;
+mod open_options;
+mod overlay;
 
 pub use file::File;
+pub use open_options::OpenOptions;
+pub use fs::{copy, rename};
 pub use config::Config;
-pub use scope::{backpack_with_config, backpack};
+pub use scope::{backpack_with_config, backpack, backpack_returning, release_shared, with_backpack};
 
 #[cfg(test)]
 mod tests {
     use std::io;
-    use std::io::Write;
-    use crate::dropin::{backpack_with_config, Config};
+    use std::io::{Read, Seek, Write};
+    use std::sync::Arc;
+    use rayon::prelude::*;
+    use crate::{BackPack, InMemoryFile};
+    use crate::dropin::{backpack, backpack_returning, backpack_with_config, copy, fs, release_shared, rename, with_backpack, Config, OpenOptions};
     use crate::dropin::File;
 
     #[test]
@@ -28,4 +36,313 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_thread_local_write() -> crate::Result<()> {
+        backpack(|| -> io::Result<()> {
+            let mut f = File::create("test.txt")?;
+            writeln!(f, "this goes to the backpack")?;
+            drop(f);
+
+            let mut contents = String::new();
+            File::open("test.txt")?.read_to_string(&mut contents)?;
+            assert_eq!(contents, "this goes to the backpack\n");
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_rename_copy() -> crate::Result<()> {
+        backpack(|| -> io::Result<()> {
+            writeln!(File::create("a.txt")?, "contents")?;
+
+            assert_eq!(copy("a.txt", "b.txt")?, 9);
+            rename("a.txt", "c.txt")?;
+            assert_eq!(File::open("a.txt").err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+            assert_eq!(rename("b.txt", "c.txt").map_err(|e| e.kind()), Err(io::ErrorKind::AlreadyExists));
+
+            // copies are separate files, even though they share their contents
+            writeln!(File::open("b.txt")?, "changed")?;
+            let mut contents = String::new();
+            File::open("c.txt")?.read_to_string(&mut contents)?;
+            assert_eq!(contents, "contents\n");
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_fs() -> crate::Result<()> {
+        backpack(|| -> io::Result<()> {
+            fs::create_dir_all("assets/textures")?;
+            fs::write("assets/a.txt", "hello")?;
+            assert_eq!(fs::read("assets/a.txt")?, b"hello");
+            assert_eq!(fs::read_to_string("./assets/a.txt")?, "hello");
+
+            assert!(fs::exists("assets/textures"));
+            assert!(fs::metadata("assets/textures")?.is_dir());
+            assert_eq!(fs::metadata("assets/a.txt")?.len(), 5);
+
+            // existing files aren't replaced
+            fs::write("assets/b.txt", "world")?;
+            assert_eq!(fs::rename("assets/a.txt", "assets/b.txt").map_err(|e| e.kind()), Err(io::ErrorKind::AlreadyExists));
+            assert_eq!(fs::copy("assets/a.txt", "assets/b.txt").map_err(|e| e.kind()), Err(io::ErrorKind::AlreadyExists));
+            assert_eq!(fs::read_to_string("assets/b.txt")?, "world");
+
+            fs::remove_file("assets/a.txt")?;
+            assert!(!fs::exists("assets/a.txt"));
+            assert_eq!(fs::read("assets/a.txt").map_err(|e| e.kind()), Err(io::ErrorKind::NotFound));
+
+            Ok(())
+        })?;
+
+        backpack_with_config(Config::default().create_in_memory(), || -> io::Result<()> {
+            fs::write("test.txt", "lost when closed")?;
+            assert!(!fs::exists("test.txt"));
+            assert_eq!(fs::read("test.txt").map_err(|e| e.kind()), Err(io::ErrorKind::NotFound));
+            assert_eq!(fs::read_to_string("test.txt").map_err(|e| e.kind()), Err(io::ErrorKind::NotFound));
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_open_options() -> crate::Result<()> {
+        backpack(|| -> io::Result<()> {
+            fs::write("log.txt", "first\n")?;
+
+            let mut f = OpenOptions::new().append(true).open("log.txt")?;
+            f.write_all(b"second\n")?;
+            f.rewind()?;
+            f.write_all(b"third\n")?;
+            drop(f);
+            assert_eq!(fs::read_to_string("log.txt")?, "first\nsecond\nthird\n");
+
+            let err = OpenOptions::new().write(true).create_new(true).open("log.txt").err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+            let mut f = OpenOptions::new().write(true).create_new(true).open("new.txt")?;
+            f.write_all(b"new")?;
+            drop(f);
+            assert_eq!(fs::read_to_string("new.txt")?, "new");
+
+            // without create, missing files aren't created
+            let err = OpenOptions::new().write(true).open("missing.txt").err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+            // create keeps the contents of existing files, truncate removes them
+            OpenOptions::new().write(true).create(true).open("new.txt")?;
+            assert_eq!(fs::read_to_string("new.txt")?, "new");
+            OpenOptions::new().write(true).truncate(true).open("new.txt")?;
+            assert_eq!(fs::read_to_string("new.txt")?, "");
+
+            let err = OpenOptions::new().read(true).truncate(true).open("new.txt").err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+            Ok(())
+        })?;
+
+        backpack_with_config(Config::default().create_in_memory(), || -> io::Result<()> {
+            let mut f = OpenOptions::new().read(true).write(true).create_new(true).open("test.txt")?;
+            f.write_all(b"in memory")?;
+            f.rewind()?;
+            let mut contents = String::new();
+            f.read_to_string(&mut contents)?;
+            assert_eq!(contents, "in memory");
+
+            Ok(())
+        })?;
+
+        // only one of the threads sharing a backpack creates a new file
+        let config = Config::shared("test_open_options");
+        let created = (0..16).into_par_iter()
+            .filter(|_| backpack_with_config(&config, || OpenOptions::new().write(true).create_new(true).open("lock").is_ok()))
+            .count();
+        assert_eq!(created, 1);
+        release_shared("test_open_options");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_overlay() -> crate::Result<()> {
+        let dir = std::env::temp_dir().join(format!("backpack_test_overlay_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("a.txt"), "on disk")?;
+        std::fs::write(dir.join("b.txt"), "removed")?;
+
+        backpack_with_config(Config::overlay(), || -> io::Result<()> {
+            assert_eq!(fs::read_to_string(dir.join("a.txt"))?, "on disk");
+
+            // writes only change the backpack
+            let mut f = OpenOptions::new().append(true).open(dir.join("a.txt"))?;
+            f.write_all(b", then in the backpack")?;
+            drop(f);
+            assert_eq!(fs::read_to_string(dir.join("a.txt"))?, "on disk, then in the backpack");
+            fs::write(dir.join("c.txt"), "created")?;
+            assert_eq!(fs::read_to_string(dir.join("c.txt"))?, "created");
+
+            fs::remove_file(dir.join("b.txt"))?;
+            assert!(!fs::exists(dir.join("b.txt")));
+            assert_eq!(fs::read(dir.join("b.txt")).map_err(|e| e.kind()), Err(io::ErrorKind::NotFound));
+            assert_eq!(fs::remove_file(dir.join("b.txt")).map_err(|e| e.kind()), Err(io::ErrorKind::NotFound));
+
+            assert_eq!(fs::copy(dir.join("c.txt"), dir.join("a.txt")).map_err(|e| e.kind()), Err(io::ErrorKind::AlreadyExists));
+            fs::rename(dir.join("a.txt"), dir.join("b.txt"))?;
+            assert!(!fs::exists(dir.join("a.txt")));
+            assert_eq!(fs::read_to_string(dir.join("b.txt"))?, "on disk, then in the backpack");
+
+            Ok(())
+        })?;
+
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt"))?, "on disk");
+        assert_eq!(std::fs::read_to_string(dir.join("b.txt"))?, "removed");
+        assert!(!dir.join("c.txt").exists());
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_shared() -> crate::Result<()> {
+        let config = Config::shared("test_shared");
+        backpack_with_config(&config, || fs::write("shared.txt", "written by one scope"))?;
+
+        // other threads see the file as well
+        let contents = rayon::join(
+            || backpack_with_config(&config, || fs::read_to_string("shared.txt")),
+            || backpack_with_config(&config, || fs::read_to_string("shared.txt")),
+        );
+        assert_eq!(contents.0?, "written by one scope");
+        assert_eq!(contents.1?, "written by one scope");
+
+        // but other backpacks don't
+        let other = backpack_with_config(Config::shared("test_shared_other"), || fs::exists("shared.txt"));
+        assert!(!other);
+
+        // released backpacks are replaced by a new one, but files still use the old one
+        let mut f = backpack_with_config(&config, || File::open("shared.txt"))?;
+        let released = release_shared("test_shared").map(|bp| Arc::downgrade(&bp));
+        assert!(release_shared("test_shared").is_none());
+        assert!(!backpack_with_config(&config, || fs::exists("shared.txt")));
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+        assert_eq!(contents, "written by one scope");
+        drop(f);
+        assert!(released.is_some_and(|bp| bp.upgrade().is_none()));
+
+        let bp = Arc::new(BackPack::create(InMemoryFile::unnamed())?);
+        with_backpack(&bp, || fs::write("owned.txt", "owned"))?;
+        let mut f = with_backpack(&bp, || File::open("owned.txt"))?;
+        let g = f.try_clone()?;
+        let owned = Arc::downgrade(&bp);
+        drop(bp);
+
+        // the files keep the backpack alive, and can still be used when they're its last owners
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+        assert_eq!(contents, "owned");
+        writeln!(f, " and changed")?;
+        drop(f);
+        assert!(owned.upgrade().is_some());
+        assert_eq!(g.metadata()?.len(), 18);
+        drop(g);
+        assert!(owned.upgrade().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_persist() -> crate::Result<()> {
+        let (res, bp) = backpack_returning(Config::thread_local(), || {
+            fs::create_dir_all("assets")?;
+            fs::write("assets/a.txt", "returned")
+        })?;
+        res?;
+        assert_eq!(bp.metadata("assets/a.txt")?.len(), 8);
+        Arc::try_unwrap(bp).ok().expect("no files are open").close()?;
+
+        // the next scope gets a new backpack
+        assert!(!backpack(|| fs::exists("assets/a.txt")));
+
+        let path = std::env::temp_dir().join(format!("backpack_test_persist_{}.bp", std::process::id()));
+        backpack_with_config(Config::thread_local().persist_to(&path), || fs::write("b.txt", "persisted"))?;
+
+        let saved = BackPack::open_mmap(&path)?;
+        assert_eq!(&*saved.get_file("b.txt")?.try_get_bytes()?, b"persisted");
+        saved.close()?;
+        std::fs::remove_file(&path)?;
+
+        // failing to save is returned, along with a panic in the scope
+        let missing = std::env::temp_dir().join("backpack_test_persist_missing").join("c.bp");
+        let res = backpack_returning(Config::thread_local().persist_to(&missing), || fs::write("c.txt", "lost"));
+        assert!(res.is_err());
+        let panic = std::panic::catch_unwind(|| backpack(|| std::panic::panic_any(42)));
+        assert_eq!(panic.err().and_then(|p| p.downcast::<i32>().ok()).map(|p| *p), Some(42));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_scope_cleanup() -> crate::Result<()> {
+        backpack(|| -> io::Result<()> {
+            fs::write("outer.txt", "outer")?;
+
+            // nested scopes may run on the same thread, but get their own backpack
+            backpack(|| -> io::Result<()> {
+                assert!(!fs::exists("outer.txt"));
+                fs::write("inner.txt", "inner")
+            })?;
+
+            assert!(fs::exists("outer.txt"));
+            assert!(!fs::exists("inner.txt"));
+            Ok(())
+        })?;
+
+        // backpacks are released when the scope ends
+        let (res, bp) = backpack_returning(Config::thread_local(), || fs::write("a.txt", "released"))?;
+        res?;
+        let released = Arc::downgrade(&bp);
+        drop(bp);
+        assert!(released.upgrade().is_none());
+
+        // unless files in it are still open
+        let (f, bp) = backpack_returning(Config::thread_local(), || {
+            fs::write("a.txt", "open")?;
+            File::open("a.txt")
+        })?;
+        let released = Arc::downgrade(&bp);
+        drop(bp);
+        let mut f = f?;
+        assert!(released.upgrade().is_some());
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+        assert_eq!(contents, "open");
+        drop(f);
+        assert!(released.upgrade().is_none());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    pub fn test_async() -> crate::Result<()> {
+        use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+        backpack(|| futures::executor::block_on(async {
+            AsyncWriteExt::write_all(&mut File::create("test.txt")?, b"async").await?;
+            let mut contents = Vec::new();
+            AsyncReadExt::read_to_end(&mut File::open("test.txt")?, &mut contents).await?;
+            assert_eq!(contents, b"async");
+            Ok::<_, io::Error>(())
+        }))?;
+
+        Ok(())
+    }
 }
\ No newline at end of file
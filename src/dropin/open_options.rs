@@ -0,0 +1,152 @@
+use std::io::{Error as IoError, ErrorKind, Result, Seek, SeekFrom};
+use std::path::Path;
+use crate::dropin::config::OpenPolicy;
+use crate::dropin::{overlay, File};
+use crate::dropin::scope::{get_backpack, with_config};
+use crate::error::PackError;
+use crate::InMemoryFile;
+
+/// Drop-in replacement for [`std::fs::OpenOptions`], which opens files
+/// according to the [`OpenPolicy`] of the current [`Config`](crate::dropin::Config).
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl OpenOptions {
+    /// Creates a blank set of options, with every option set to `false`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every write goes to the end of the file, wherever the cursor was
+    /// moved to. Implies [`write`](OpenOptions::write).
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Only creates a new file, failing with [`ErrorKind::AlreadyExists`]
+    /// if one exists. Overrides [`create`](OpenOptions::create) and [`truncate`](OpenOptions::truncate).
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Rejects the same combinations of options as [`std::fs::OpenOptions`] does
+    fn check(&self) -> Result<()> {
+        let writable = self.write || self.append;
+        if !self.read && !writable {
+            return Err(IoError::new(ErrorKind::InvalidInput, "a file has to be opened for reading or writing"));
+        }
+        if (self.truncate || self.create || self.create_new) && !writable {
+            return Err(IoError::new(ErrorKind::InvalidInput, "creating or truncating a file requires write or append access"));
+        }
+        if self.truncate && self.append && !self.create_new {
+            return Err(IoError::new(ErrorKind::InvalidInput, "a file can't be truncated and appended to"));
+        }
+        Ok(())
+    }
+
+    pub fn open<'f, P: AsRef<Path>>(&self, path: P) -> Result<File<'f, 'static>> {
+        self.check()?;
+
+        with_config(|config| {
+            match config.open_policy {
+                OpenPolicy::OnDisk => {
+                    let file = std::fs::OpenOptions::new()
+                        .read(self.read)
+                        .write(self.write)
+                        .append(self.append)
+                        .truncate(self.truncate)
+                        .create(self.create)
+                        .create_new(self.create_new)
+                        .open(path)?;
+                    // appending is done by the os
+                    Ok(File::new(file.into(), false))
+                }
+                OpenPolicy::InMemory => {
+                    Ok(File::new(InMemoryFile::new(path).into(), self.append))
+                }
+                OpenPolicy::ThreadLocalBackpack |
+                OpenPolicy::Shared(_) => self.open_in_backpack(path.as_ref()),
+                OpenPolicy::Overlay => self.open_overlay(path.as_ref()),
+            }
+        })
+    }
+
+    fn open_in_backpack<'f>(&self, name: &Path) -> Result<File<'f, 'static>> {
+        File::in_backpack(get_backpack(), self.append, |bp| {
+            // checking whether the file exists and creating it is one step for create_new,
+            // since other threads may create the file as well with a shared backpack
+            let mut file = match bp.metadata(name) {
+                _ if self.create_new => bp.create_new_file(name),
+                Err(PackError::FileNotFound(_)) if self.create => bp.add_empty_file(name),
+                _ => bp.get_file(name),
+            }?;
+
+            if self.truncate && !self.create_new {
+                file.set_len(0)?;
+            }
+            if self.append {
+                file.seek(SeekFrom::End(0))?;
+            }
+            Ok(file)
+        })
+    }
+
+    /// Files which weren't written in the overlay are read from disk, and
+    /// copied into the thread-local backpack when they're opened for writing.
+    fn open_overlay<'f>(&self, path: &Path) -> Result<File<'f, 'static>> {
+        let name = overlay::name(path)?;
+
+        if !overlay::in_backpack(&name) {
+            if let Ok(metadata) = overlay::disk_metadata(path, &name) {
+                if self.create_new {
+                    return Err(PackError::FileExists(path.to_path_buf()).into());
+                }
+                if !self.write && !self.append {
+                    return Ok(File::new(std::fs::File::open(path)?.into(), false));
+                }
+                if metadata.is_dir() {
+                    return Err(PackError::IsADirectory(path.to_path_buf()).into());
+                }
+
+                if self.truncate {
+                    File::in_backpack(get_backpack(), false, |bp| bp.add_empty_file(&name))?;
+                } else {
+                    overlay::copy_up(path, &name)?;
+                }
+            }
+        }
+
+        let file = self.open_in_backpack(Path::new(&name))?;
+        overlay::clear_whiteout(&name);
+        Ok(file)
+    }
+}
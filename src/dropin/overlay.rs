@@ -0,0 +1,131 @@
+//! The [`OpenPolicy::Overlay`](crate::dropin::config::OpenPolicy::Overlay) puts the
+//! thread-local backpack on top of the disk. Files are stored in the backpack under
+//! their absolute path without the root, so `/tmp/a.txt` is named `tmp/a.txt`.
+//! Files on disk are copied into the backpack before they're changed, and removed
+//! files on disk are hidden by whiteouts.
+
+use std::io::{Error as IoError, Result};
+use std::path::{Component, Path, PathBuf};
+use crate::dropin::File;
+use crate::dropin::scope::{get_backpack, with_whiteouts};
+use crate::error::PackError;
+use crate::pack::EntryMetadata;
+use crate::pack::path::normalize;
+
+/// Name of a file on disk in the thread-local backpack
+pub(crate) fn name(path: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+
+    let relative = absolute.components()
+        .filter(|c| !matches!(c, Component::Prefix(_) | Component::RootDir))
+        .collect::<PathBuf>();
+    normalize(&relative).map_err(Into::into)
+}
+
+fn is_whiteout(name: &str) -> bool {
+    with_whiteouts(|w| w.contains(name))
+}
+
+fn whiteout(name: String) {
+    with_whiteouts(|w| w.insert(name));
+}
+
+/// Called when a file is created in the backpack, which makes it visible again
+pub(crate) fn clear_whiteout(name: &str) {
+    with_whiteouts(|w| w.remove(name));
+}
+
+/// Whether the file or directory is in the thread-local backpack
+pub(crate) fn in_backpack(name: &str) -> bool {
+    get_backpack().metadata(name).is_ok()
+}
+
+/// Metadata of the file on disk, unless it was removed in the overlay
+pub(crate) fn disk_metadata(path: impl AsRef<Path>, name: &str) -> Result<std::fs::Metadata> {
+    if is_whiteout(name) {
+        return Err(PackError::FileNotFound(path.as_ref().to_path_buf()).into());
+    }
+    std::fs::metadata(path)
+}
+
+/// Copies a file on disk into the thread-local backpack, so it can be changed there
+pub(crate) fn copy_up(path: impl AsRef<Path>, name: &str) -> Result<()> {
+    let path = path.as_ref();
+    if disk_metadata(path, name)?.is_dir() {
+        return Err(PackError::IsADirectory(path.to_path_buf()).into());
+    }
+    File::in_backpack(get_backpack(), false, |bp| bp.add_file_named(std::fs::File::open(path)?, name))?;
+    Ok(())
+}
+
+pub(crate) fn remove_file(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let name = name(path)?;
+    if in_backpack(&name) {
+        get_backpack().remove_file(&name).map_err(Into::<IoError>::into)?;
+    } else if disk_metadata(path, &name)?.is_dir() {
+        return Err(PackError::IsADirectory(path.to_path_buf()).into());
+    }
+
+    if disk_metadata(path, &name).is_ok() {
+        whiteout(name);
+    }
+    Ok(())
+}
+
+/// Files on disk at `to` aren't replaced, like the ones in the backpack aren't
+fn check_target(to: impl AsRef<Path>, name: &str) -> Result<()> {
+    if !in_backpack(name) && disk_metadata(&to, name).is_ok() {
+        return Err(PackError::FileExists(to.as_ref().to_path_buf()).into());
+    }
+    Ok(())
+}
+
+pub(crate) fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+    let (from_name, to_name) = (name(&from)?, name(&to)?);
+    check_target(&to, &to_name)?;
+    if !in_backpack(&from_name) {
+        copy_up(&from, &from_name)?;
+    }
+    get_backpack().rename(&from_name, &to_name).map_err(Into::<IoError>::into)?;
+
+    if disk_metadata(&from, &from_name).is_ok() {
+        whiteout(from_name);
+    }
+    clear_whiteout(&to_name);
+    Ok(())
+}
+
+pub(crate) fn copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<u64> {
+    let (from_name, to_name) = (name(&from)?, name(&to)?);
+    check_target(&to, &to_name)?;
+    if !in_backpack(&from_name) {
+        copy_up(&from, &from_name)?;
+    }
+    let bp = get_backpack();
+    bp.copy(&from_name, &to_name).map_err(Into::<IoError>::into)?;
+
+    clear_whiteout(&to_name);
+    Ok(bp.metadata(&to_name).map_err(Into::<IoError>::into)?.len())
+}
+
+pub(crate) fn metadata(path: impl AsRef<Path>) -> Result<EntryMetadata> {
+    let name = name(&path)?;
+    match get_backpack().metadata(&name) {
+        Ok(metadata) => Ok(metadata),
+        Err(_) => disk_metadata(&path, &name).map(|m| (&m).into()),
+    }
+}
+
+pub(crate) fn create_dir_all(path: impl AsRef<Path>) -> Result<()> {
+    let name = name(&path)?;
+    match disk_metadata(&path, &name) {
+        Ok(m) if m.is_file() && !in_backpack(&name) => Err(PackError::FileExists(path.as_ref().to_path_buf()).into()),
+        _ => get_backpack().create_dir_all(&name).map_err(Into::into),
+    }
+}
@@ -1,15 +1,24 @@
-use crate::dropin::config::Config;
-use crate::{BackPack, InMemoryFile};
+use crate::dropin::config::{Config, OpenPolicy};
+use crate::{error, BackPack, InMemoryFile};
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
 use lazy_static::lazy_static;
-use elsa::sync::FrozenMap;
-use std::thread::ThreadId;
+use parking_lot::Mutex;
+
+/// A backpack of the drop-in layer. Files opened in it keep it alive.
+pub(crate) type SharedBackPack = Arc<BackPack<'static, 'static>>;
 
 thread_local! {
     static TL_CONFIG: RefCell<Config> = RefCell::new(Config::default());
+    /// The backpack of the current scope, created when it's first used
+    static TL_BACKPACK: RefCell<Option<SharedBackPack>> = const { RefCell::new(None) };
+    /// Files on disk which were removed in the overlay of the current scope
+    static TL_WHITEOUTS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
 }
 lazy_static! {
-    static ref TL_BACKPACKS: FrozenMap<ThreadId, Box<BackPack<'static, 'static>>> = FrozenMap::new();
+    static ref SHARED_BACKPACKS: Mutex<HashMap<String, SharedBackPack>> = Mutex::new(HashMap::new());
 }
 
 
@@ -19,25 +28,97 @@ pub(crate) fn with_config<T>(f: impl FnOnce(std::cell::Ref<Config>) -> T) -> T {
     })
 }
 
-pub(crate) fn get_backpack() -> &'static BackPack<'static, 'static> {
-    let res = TL_BACKPACKS.get(&std::thread::current().id());
-    if let Some(i) = res {
-        i
-    } else {
-        TL_BACKPACKS.insert(
-            std::thread::current().id(),
-            Box::new(BackPack::create(InMemoryFile::unnamed()).expect("failed to create backpack"))
-        )
+pub(crate) fn with_whiteouts<T>(f: impl FnOnce(&mut HashSet<String>) -> T) -> T {
+    TL_WHITEOUTS.with(|whiteouts| {
+        f(&mut whiteouts.borrow_mut())
+    })
+}
+
+/// Backpacks of scopes only live in memory, so dropping them doesn't save them
+fn new_backpack() -> SharedBackPack {
+    let mut bp = BackPack::create(InMemoryFile::unnamed()).expect("failed to create backpack");
+    bp.discard_on_drop();
+    Arc::new(bp)
+}
+
+/// The backpack of the current scope: the one passed to [`with_backpack`], the
+/// named backpack of [`OpenPolicy::Shared`], or else a new one for the scope.
+fn scope_backpack() -> SharedBackPack {
+    let shared = with_config(|config| match &config.open_policy {
+        OpenPolicy::Shared(name) => Some(name.clone()),
+        _ => None,
+    });
+
+    match shared {
+        Some(name) => SHARED_BACKPACKS.lock().entry(name).or_insert_with(new_backpack).clone(),
+        None => new_backpack(),
+    }
+}
+
+/// Releases the backpack of [`OpenPolicy::Shared`] scopes named `name`, which is kept
+/// for the whole process otherwise. Returns it, if any scope used it, so it can still be
+/// saved. It's dropped once the scopes and files still using it are done with it, and
+/// scopes which use the name afterwards get a new backpack.
+///
+/// ```rust
+/// # use backpack::PackError;
+/// # use backpack::dropin::{backpack_with_config, fs, release_shared, Config};
+/// # fn main() -> Result<(), PackError> {
+///     let config = Config::shared("assets");
+///     backpack_with_config(&config, || fs::write("test.txt", "shared"))?;
+///
+///     let bp = release_shared("assets").expect("the backpack was used");
+///     assert_eq!(bp.metadata("test.txt")?.len(), 6);
+///     assert!(!backpack_with_config(&config, || fs::exists("test.txt")));
+/// #   Ok(())
+/// # }
+/// ```
+pub fn release_shared(name: &str) -> Option<SharedBackPack> {
+    SHARED_BACKPACKS.lock().remove(name)
+}
+
+pub(crate) fn get_backpack() -> SharedBackPack {
+    TL_BACKPACK.with(|bp| {
+        bp.borrow_mut().get_or_insert_with(scope_backpack).clone()
+    })
+}
+
+/// The configuration, backpack and whiteouts of a scope on a thread
+struct Scope {
+    config: Config,
+    backpack: Option<SharedBackPack>,
+    whiteouts: HashSet<String>,
+}
+
+impl Scope {
+    fn new(config: Config, backpack: Option<SharedBackPack>) -> Self {
+        Self {
+            config,
+            backpack,
+            whiteouts: HashSet::new(),
+        }
     }
 
-    // TL_BACKPACK.with(|i| {
-    //     if let Some(i) = i.get() {
-    //         i
-    //     } else {
-    //         i.set(BackPack::create(InMemoryFile::unnamed()).expect("failed to create backpack"));
-    //         get_backpack()
-    //     }
-    // })
+    /// Makes this the current scope of the thread, returning the previous one
+    fn enter(self) -> Scope {
+        Scope {
+            config: TL_CONFIG.with(|c| std::mem::replace(&mut *c.borrow_mut(), self.config)),
+            backpack: TL_BACKPACK.with(|bp| std::mem::replace(&mut *bp.borrow_mut(), self.backpack)),
+            whiteouts: TL_WHITEOUTS.with(|w| std::mem::replace(&mut *w.borrow_mut(), self.whiteouts)),
+        }
+    }
+}
+
+/// Restores the previous scope of the thread when a scope ends or panics.
+/// The backpack of the scope is released, unless files in it are still open.
+struct Restore(Option<Scope>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            previous.enter();
+        }
+    }
 }
 
 /// All code passed in the closure will execute with a default
@@ -45,25 +126,102 @@ pub fn backpack<T: Send>(f: impl Send + FnOnce() -> T) -> T {
     backpack_with_config(Config::thread_local(), f)
 }
 
+/// Runs `f` with `config`. Every call gets a new backpack, which is
+/// released when `f` returns, unless files in it are still open.
+///
+/// Failing to save the backpack to [`Config::persist_to`] is only logged,
+/// use [`backpack_returning`] to handle it.
 pub fn backpack_with_config<T: Send>(config: impl AsRef<Config>, f: impl Send + FnOnce() -> T) -> T {
-    let config = config.as_ref().clone();
+    let (value, bp) = run_scope(config.as_ref().clone(), f);
+    if let Err(e) = persist(&bp, config.as_ref()) {
+        log::error!("failed to save backpack of scope to {:?}. {}", config.as_ref().persist_to, e);
+    }
+    value
+}
+
+/// Same as [`backpack_with_config`], but also returns the backpack the files
+/// were written to. Use [`BackPack::save_as`] to store it, or [`Arc::try_unwrap`]
+/// and [`BackPack::close`] to get its serialised contents once no file is open anymore.
+///
+/// Fails when [`Config::persist_to`] is set, and saving the backpack fails.
+///
+/// ```rust
+/// # use backpack::PackError;
+/// # use backpack::dropin::{backpack_returning, fs, Config};
+/// # fn main() -> Result<(), PackError> {
+///     let (res, bp) = backpack_returning(Config::thread_local(), || fs::write("test.txt", "hello"))?;
+///     res?;
+///     assert_eq!(bp.metadata("test.txt")?.len(), 5);
+/// #   Ok(())
+/// # }
+/// ```
+pub fn backpack_returning<T: Send>(config: impl AsRef<Config>, f: impl Send + FnOnce() -> T) -> error::Result<(T, Arc<BackPack<'static, 'static>>)> {
+    let (value, bp) = run_scope(config.as_ref().clone(), f);
+    persist(&bp, config.as_ref())?;
+    Ok((value, bp))
+}
+
+/// Runs `f` in a new scope on a rayon thread. Panics in `f` are passed on as they are.
+fn run_scope<T: Send>(config: Config, f: impl Send + FnOnce() -> T) -> (T, SharedBackPack) {
     let mut res = None;
 
     rayon::scope(|s| {
         s.spawn(|_| {
-            TL_CONFIG.with(|i| {
-                *i.borrow_mut() = config;
-            });
-            res = Some(f());
-
-            // TODO: remove thread local backpack here
+            res = Some(std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let _restore = Restore(Some(Scope::new(config, None).enter()));
+                let value = f();
+                (value, get_backpack())
+            })));
         })
     });
 
-    res.expect("thread paniced")
-
+    match res.expect("spawned jobs finish before the scope ends") {
+        Ok(res) => res,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
 }
 
+/// Saves the backpack of a scope to [`Config::persist_to`], if it's set
+fn persist(bp: &BackPack, config: &Config) -> error::Result<()> {
+    match &config.persist_to {
+        Some(path) => bp.save_as(path),
+        None => Ok(()),
+    }
+}
 
+/// Runs `f` on the current thread, with all files opened in `backpack`. The
+/// [`OpenPolicy::Overlay`] is kept, any other policy acts like
+/// [`OpenPolicy::ThreadLocalBackpack`] while `f` runs.
+///
+/// The backpack can't just be borrowed: files opened in `f` can be returned from it or
+/// sent to other threads, and read from and write to the backpack for as long as they're
+/// open. That's why they keep the backpack alive, like files of scopes do. To use a
+/// backpack you own, wrap it in an [`Arc`], and get it back with [`Arc::try_unwrap`]
+/// once the files opened in it are closed.
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use backpack::{BackPack, InMemoryFile, PackError};
+/// # use backpack::dropin::{fs, with_backpack};
+/// # fn main() -> Result<(), PackError> {
+///     let bp = Arc::new(BackPack::create(InMemoryFile::unnamed())?);
+///     with_backpack(&bp, || fs::write("test.txt", "in the backpack"))?;
+///
+///     let bp = Arc::try_unwrap(bp).ok().expect("no files are open");
+///     assert_eq!(bp.metadata("test.txt")?.len(), 15);
+/// #   bp.close()?;
+/// #   Ok(())
+/// # }
+/// ```
+pub fn with_backpack<T>(backpack: &Arc<BackPack<'static, 'static>>, f: impl FnOnce() -> T) -> T {
+    let config = with_config(|config| match config.open_policy {
+        OpenPolicy::Overlay => config.clone(),
+        _ => Config {
+            open_policy: OpenPolicy::ThreadLocalBackpack,
+            ..config.clone()
+        },
+    });
 
-
+    let _restore = Restore(Some(Scope::new(config, Some(backpack.clone())).enter()));
+    f()
+}
@@ -25,24 +25,96 @@ pub enum PackError {
     #[error("file {0:?} not present in backpack")]
     FileNotFound(PathBuf),
 
+    #[error("file {0:?} already exists in backpack")]
+    FileExists(PathBuf),
+
+    #[error("{0:?} is not a directory in backpack")]
+    NotADirectory(PathBuf),
+
+    #[error("{0:?} is a directory in backpack")]
+    IsADirectory(PathBuf),
+
+    #[error("directory {0:?} in backpack is not empty")]
+    DirectoryNotEmpty(PathBuf),
+
+    #[error("{0:?} is not a valid name in a backpack, names can't be absolute or escape the root with '..'")]
+    InvalidName(PathBuf),
+
     #[error("attempted to pack a file which has no name")]
     NoName,
 
     #[error("invalid table of content entry in the backpack. this is a bug")]
     InvalidEntry,
+
+    #[error("file name {0:?} is too long to be stored in a backpack")]
+    NameTooLong(String),
+
+    #[error("no codec with identifier {0} is registered")]
+    UnknownCodec(u16),
+
+    #[error("corrupted entries in backpack: {0:?}")]
+    Corrupted(Vec<String>),
+
+    #[error("corrupted table of contents block at offset {0} in backpack")]
+    CorruptedToc(u64),
+
+    #[error("backpack is truncated, expected at least {0} bytes but found {1}")]
+    Truncated(u64, u64),
+
+    #[error("attempted to change a read-only backpack")]
+    ReadOnly,
+
+    #[error("backpack is encrypted, but no key was given to open it")]
+    KeyRequired,
+
+    #[error("backpack is not encrypted, but was opened with a key")]
+    NotEncrypted,
+
+    #[error("backpack was encrypted with a different key")]
+    WrongKey,
+
+    #[error("tampered entries in encrypted backpack: {0:?}")]
+    Tampered(Vec<String>),
+
+    #[error("tampered table of contents block at offset {0} in encrypted backpack")]
+    TamperedToc(u64),
+
+    #[error("tampered header in encrypted backpack")]
+    TamperedHeader,
+
+    #[error("backpack can't be read sequentially, its table of contents is neither stored before its data nor with its entries")]
+    NotStreamable,
 }
 
-impl Into<std::io::Error> for PackError {
-    fn into(self) -> IoError {
-        match self {
+impl From<PackError> for std::io::Error {
+    fn from(e: PackError) -> IoError {
+        match e {
             PackError::Io(e) => e,
             e@PackError::BadMagic |
-            e@PackError::Utf8Error(_) => IoError::new(ErrorKind::InvalidData, e),
-            e@PackError::Incompatible(_) => IoError::new(ErrorKind::Unsupported, e),
-            e@PackError::Closed => IoError::new(ErrorKind::Other, e),
+            e@PackError::Utf8Error(_) |
+            e@PackError::Corrupted(_) |
+            e@PackError::CorruptedToc(_) |
+            e@PackError::Truncated(..) |
+            e@PackError::NotEncrypted |
+            e@PackError::Tampered(_) |
+            e@PackError::TamperedToc(_) |
+            e@PackError::TamperedHeader => IoError::new(ErrorKind::InvalidData, e),
+            e@PackError::Incompatible(_) |
+            e@PackError::NotStreamable |
+            e@PackError::UnknownCodec(_) => IoError::new(ErrorKind::Unsupported, e),
+            e@PackError::Closed => IoError::other(e),
+            e@PackError::ReadOnly |
+            e@PackError::KeyRequired |
+            e@PackError::WrongKey => IoError::new(ErrorKind::PermissionDenied, e),
             e@PackError::FileNotFound(_) => IoError::new(ErrorKind::NotFound, e),
+            e@PackError::FileExists(_) => IoError::new(ErrorKind::AlreadyExists, e),
+            e@PackError::NotADirectory(_) => IoError::new(ErrorKind::NotADirectory, e),
+            e@PackError::IsADirectory(_) => IoError::new(ErrorKind::IsADirectory, e),
+            e@PackError::DirectoryNotEmpty(_) => IoError::new(ErrorKind::DirectoryNotEmpty, e),
+            e@PackError::NameTooLong(_) |
+            e@PackError::InvalidName(_) => IoError::new(ErrorKind::InvalidInput, e),
             e@PackError::NoName |
-            e@PackError::InvalidEntry => IoError::new(ErrorKind::Other, e)
+            e@PackError::InvalidEntry => IoError::other(e)
         }
     }
 }
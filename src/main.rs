@@ -0,0 +1,203 @@
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use backpack::pack::PACK_MAGIC;
+use backpack::pack::{DirEntry, PackWriter};
+use backpack::{BackPack, PackError, RawFile, Result};
+
+const USAGE: &str = "\
+usage: backpack <command> <pack> [args...]
+
+commands:
+    create <pack> [files...]   create a new pack containing files and directories
+    list <pack>                list the files in a pack
+    extract <pack> [dir]       extract all files of a pack into dir (default: .)
+    add <pack> <files...>      add files and directories to a pack
+    rm <pack> <names...>       remove files from a pack
+    cat <pack> <names...>      write the contents of files in a pack to stdout
+    info <pack>                print the header of a pack
+    verify <pack>              check the checksums of all files in a pack";
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (command, pack, rest) = match args.as_slice() {
+        [command, pack, rest @ ..] => (command.as_str(), Path::new(pack), rest),
+        _ => return usage(),
+    };
+
+    let res = match (command, rest) {
+        ("create", files) => create(pack, files),
+        ("list", []) => list(pack),
+        ("extract", []) => extract(pack, Path::new(".")),
+        ("extract", [dir]) => extract(pack, Path::new(dir)),
+        ("add", files @ [_, ..]) => add(pack, files),
+        ("rm", names @ [_, ..]) => rm(pack, names),
+        ("cat", names @ [_, ..]) => cat(pack, names),
+        ("info", []) => info(pack),
+        ("verify", []) => verify(pack),
+        _ => return usage(),
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("backpack: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from(2)
+}
+
+/// Opens a pack for changing it. [`RawFile::open`] only opens files for reading.
+fn open_writable(pack: &Path) -> Result<RawFile<'static, 'static>> {
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(pack)?;
+    Ok(RawFile::from(file).with_name(pack))
+}
+
+/// Where [`add_paths`] puts files: an open pack, or a new pack which is being written
+trait AddFiles {
+    fn add_file(&mut self, path: &Path, name: PathBuf) -> Result<()>;
+    fn create_dir_all(&mut self, name: &Path) -> Result<()>;
+}
+
+impl<'f> AddFiles for &'f BackPack<'f, 'static> {
+    fn add_file(&mut self, path: &Path, name: PathBuf) -> Result<()> {
+        BackPack::add_file(self, RawFile::open(path)?.with_name(name))?;
+        Ok(())
+    }
+
+    fn create_dir_all(&mut self, name: &Path) -> Result<()> {
+        BackPack::create_dir_all(self, name)
+    }
+}
+
+impl AddFiles for PackWriter<'_, 'static> {
+    fn add_file(&mut self, path: &Path, name: PathBuf) -> Result<()> {
+        PackWriter::add_file(self, RawFile::open(path)?.with_name(name))
+    }
+
+    fn create_dir_all(&mut self, name: &Path) -> Result<()> {
+        PackWriter::create_dir_all(self, name)
+    }
+}
+
+/// Adds files to the pack under the name they were given with, without
+/// the root of absolute paths. Directories are added recursively.
+fn add_paths(bp: &mut impl AddFiles, paths: impl IntoIterator<Item=PathBuf>) -> Result<()> {
+    for path in paths {
+        let name = path.components()
+            .filter(|c| !matches!(c, Component::Prefix(_) | Component::RootDir))
+            .collect::<PathBuf>();
+
+        if path.is_dir() {
+            let mut children = std::fs::read_dir(&path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            children.sort();
+
+            if children.is_empty() {
+                bp.create_dir_all(&name)?;
+            }
+            add_paths(bp, children)?;
+        } else {
+            bp.add_file(&path, name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams the files into the new pack, so they don't have to fit in memory.
+fn create(pack: &Path, files: &[String]) -> Result<()> {
+    let mut writer = PackWriter::create(RawFile::create(pack)?)?;
+    add_paths(&mut writer, files.iter().map(PathBuf::from))?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn list(pack: &Path) -> Result<()> {
+    let bp = BackPack::open_mmap(pack)?;
+    for entry in &bp {
+        println!("{:>12}  {}", entry.size(), entry.name());
+    }
+    bp.close()?;
+    Ok(())
+}
+
+fn extract(pack: &Path, dir: &Path) -> Result<()> {
+    let bp = BackPack::open_mmap(pack)?;
+    extract_dir(&bp, "", dir)?;
+    bp.close()?;
+    Ok(())
+}
+
+fn extract_dir<'f>(bp: &'f BackPack<'f, 'static>, name: &str, dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir.join(name))?;
+
+    for entry in bp.read_dir(name)? {
+        match entry {
+            DirEntry::File(f) => std::fs::write(dir.join(f.path()), &*f.open().try_get_bytes()?)?,
+            DirEntry::Directory(subdir) => extract_dir(bp, &subdir, dir)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn add(pack: &Path, files: &[String]) -> Result<()> {
+    let bp = BackPack::open(open_writable(pack)?)?;
+    add_paths(&mut &bp, files.iter().map(PathBuf::from))?;
+    bp.close()?;
+    Ok(())
+}
+
+fn rm(pack: &Path, names: &[String]) -> Result<()> {
+    let bp = BackPack::open(open_writable(pack)?)?;
+    for name in names {
+        bp.remove_file(name)?;
+    }
+    bp.close()?;
+    Ok(())
+}
+
+fn cat(pack: &Path, names: &[String]) -> Result<()> {
+    let bp = BackPack::open_mmap(pack)?;
+    let mut stdout = std::io::stdout().lock();
+    for name in names {
+        stdout.write_all(&bp.get_file(name)?.try_get_bytes()?)?;
+    }
+    stdout.flush()?;
+    bp.close()?;
+    Ok(())
+}
+
+fn info(pack: &Path) -> Result<()> {
+    let bp = BackPack::open_mmap(pack)?;
+    println!("magic:       {}", String::from_utf8_lossy(PACK_MAGIC));
+    println!("version:     {}", bp.version());
+    println!("size:        {} bytes", bp.data_size());
+    println!("toc blocks:  {}", bp.toc_block_count());
+    println!("files:       {}", bp.entries().len());
+    bp.close()?;
+    Ok(())
+}
+
+fn verify(pack: &Path) -> Result<()> {
+    let bp = BackPack::open_mmap(pack)?;
+    let res = bp.verify();
+    match &res {
+        Ok(()) => println!("ok"),
+        Err(PackError::Corrupted(names)) => {
+            for name in names {
+                println!("corrupted: {}", name);
+            }
+        }
+        Err(_) => {}
+    }
+    bp.close()?;
+    res
+}
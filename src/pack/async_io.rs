@@ -0,0 +1,136 @@
+//! Async I/O for packs, behind the `async` feature. Files implement the
+//! [`futures::io`] traits by doing the work in `poll`, like
+//! [`futures::io::AllowStdIo`] does. This never blocks for contents in memory,
+//! but reading and writing files on disk or entries of partially parsed packs
+//! blocks the executor thread. Opening, flushing and closing packs is done on
+//! a shared pool of threads for blocking work instead.
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use futures::channel::oneshot;
+use lazy_static::lazy_static;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use crate::error;
+use crate::error::PackError;
+use crate::pack::{BackPack, InMemoryFile, RawFile};
+
+/// Implements the [`futures::io`] traits with the std ones. They block the executor thread
+/// when the std ones do, see the [module documentation](self).
+macro_rules! impl_async_io {
+    ($($t:ty),*) => {$(
+        impl ::futures::io::AsyncRead for $t {
+            fn poll_read(self: ::std::pin::Pin<&mut Self>, _: &mut ::std::task::Context<'_>, buf: &mut [u8]) -> ::std::task::Poll<std::io::Result<usize>> {
+                ::std::task::Poll::Ready(::std::io::Read::read(self.get_mut(), buf))
+            }
+        }
+
+        impl ::futures::io::AsyncWrite for $t {
+            fn poll_write(self: ::std::pin::Pin<&mut Self>, _: &mut ::std::task::Context<'_>, buf: &[u8]) -> ::std::task::Poll<std::io::Result<usize>> {
+                ::std::task::Poll::Ready(::std::io::Write::write(self.get_mut(), buf))
+            }
+
+            fn poll_flush(self: ::std::pin::Pin<&mut Self>, _: &mut ::std::task::Context<'_>) -> ::std::task::Poll<std::io::Result<()>> {
+                ::std::task::Poll::Ready(::std::io::Write::flush(self.get_mut()))
+            }
+
+            fn poll_close(self: ::std::pin::Pin<&mut Self>, cx: &mut ::std::task::Context<'_>) -> ::std::task::Poll<std::io::Result<()>> {
+                self.poll_flush(cx)
+            }
+        }
+
+        impl ::futures::io::AsyncSeek for $t {
+            fn poll_seek(self: ::std::pin::Pin<&mut Self>, _: &mut ::std::task::Context<'_>, pos: ::std::io::SeekFrom) -> ::std::task::Poll<std::io::Result<u64>> {
+                ::std::task::Poll::Ready(::std::io::Seek::seek(self.get_mut(), pos))
+            }
+        }
+    )*};
+}
+pub(crate) use impl_async_io;
+
+impl_async_io!(RawFile<'_, '_>, InMemoryFile<'_, '_>);
+
+lazy_static! {
+    /// Threads for blocking work, kept apart from the global rayon pool
+    /// so waiting for disks doesn't hold up computations.
+    static ref BLOCKING_POOL: ThreadPool = ThreadPoolBuilder::new()
+        .thread_name(|i| format!("backpack-blocking-{}", i))
+        .build()
+        .expect("failed to start blocking thread pool");
+}
+
+/// Runs `f` on the [blocking pool](BLOCKING_POOL). When the future is dropped
+/// before `f` finished, its result is passed to `cancelled` instead. Panics in `f`
+/// are passed on to the future, since the pool would abort on them.
+fn unblock<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static, cancelled: fn(T)) -> impl Future<Output=T> {
+    let (tx, rx) = oneshot::channel();
+    BLOCKING_POOL.spawn(move || {
+        let res = std::panic::catch_unwind(AssertUnwindSafe(f));
+        if let Err(Ok(res)) = tx.send(res) {
+            cancelled(res);
+        }
+    });
+
+    async move {
+        match rx.await.expect("blocking pool shut down") {
+            Ok(res) => res,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// Packs which were opened or flushed for a future which was dropped are closed,
+/// so dropping them doesn't try to flush them again.
+fn discard(bp: BackPack<'static, 'static>) {
+    let _ = bp.close_drop_unwritten_changes();
+}
+
+impl BackPack<'static, 'static> {
+    /// Same as [`open`](BackPack::open), on the blocking pool.
+    ///
+    /// ```rust
+    /// # use backpack::{BackPack, PackError, RawFile};
+    /// # fn main() -> Result<(), PackError> {
+    /// #   let path = std::env::temp_dir().join("backpack_doctest_open_async.bp");
+    /// #   let bp = BackPack::create(RawFile::create(&path)?)?;
+    /// #   bp.add_file_named("hello", "test.txt")?;
+    /// #   bp.close()?;
+    ///     futures::executor::block_on(async {
+    ///         let bp = BackPack::open_async(RawFile::open(&path)?).await?;
+    ///         assert_eq!(bp.metadata("test.txt")?.len(), 5);
+    ///         bp.close_drop_unwritten_changes()?;
+    ///         Ok::<_, PackError>(())
+    ///     })?;
+    /// #   std::fs::remove_file(path)?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub async fn open_async<E, B>(backing: B) -> error::Result<Self>
+    where
+        E: Into<PackError>,
+        B: TryInto<RawFile<'static, 'static>, Error=E> + Send + 'static,
+    {
+        unblock(move || BackPack::open(backing), |res| {
+            if let Ok(bp) = res {
+                discard(bp);
+            }
+        }).await
+    }
+
+    /// Same as [`flush`](BackPack::flush), on the blocking pool. When the
+    /// future is dropped before it completes, the pack is closed.
+    pub async fn flush_async(&mut self) -> error::Result<()> {
+        let mut bp = std::mem::replace(self, BackPack::closed());
+        let (bp, res) = unblock(move || {
+            let res = bp.flush();
+            (bp, res)
+        }, |(bp, _)| discard(bp)).await;
+
+        *self = bp;
+        res
+    }
+
+    /// Same as [`close`](BackPack::close), on the blocking pool.
+    pub async fn close_async(self) -> error::Result<RawFile<'static, 'static>> {
+        unblock(move || self.close(), |_| {}).await
+    }
+}
@@ -1,77 +1,543 @@
-use std::collections::HashMap;
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::ops::{Deref, DerefMut};
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use elsa::sync::FrozenMap;
-use parking_lot::RwLock;
+use memmap2::Mmap;
+use parking_lot::{Mutex, RwLock};
 use crate::{error, RawFile};
 use crate::pack::in_memory::InMemoryFile;
-use crate::pack::{PACK_HEADER_SIZE, PACK_MAGIC, PACK_VERSION, TOC_SIZE};
+use crate::pack::lazy::LazyFile;
+use crate::pack::{DIRECTORY, MAX_ALLOWED_IN_MEMORY, PACK_HEADER_SIZE, PACK_MAGIC, PACK_VERSION, TOC_SIZE, TOMBSTONE};
 use crate::error::PackError;
-use crate::error::PackError::{Closed, NoName};
+use crate::error::PackError::{Closed, NoName, ReadOnly};
 use crate::pack::slice::PackSlice;
-
+use crate::pack::maybe_ref::MaybeRef;
+use crate::pack::entry::{DirEntry, Entry};
+use crate::pack::metadata::{Attributes, EntryMetadata};
+use crate::pack::path::{ancestors, normalize, normalize_entry};
+use crate::pack::codec;
+use crate::pack::codec::STORE;
+use crate::pack::crypto::{Cipher, Nonce, KEY_CHECK_SIZE, NONCE_SIZE, TAG_SIZE};
+#[cfg(feature = "encryption")]
+use crate::pack::crypto::KeyProvider;
+
+mod writer;
+mod stream;
+pub use writer::PackWriter;
+pub use stream::{PackStreamReader, StreamEntry};
+
+/// Location of the format version in the header
+const VERSION_POSITION: u64 = PACK_MAGIC.len() as u64;
+/// Location of the data size in the header
+const DATA_SIZE_POSITION: u64 = VERSION_POSITION + 2;
+/// Location of the offset of the first toc block in the header
+const FIRST_TOC_OFFSET_POSITION: u64 = DATA_SIZE_POSITION + 8;
+
+/// Size of the header of a toc block: filled u16 | next toc offset u64 | checksum u32
+const TOC_BLOCK_HEADER_SIZE: usize = 2 + 8 + 4;
+
+/// Flag in the filled field of toc blocks of which the entries are encrypted
+const ENCRYPTED_TOC: u16 = 0x8000;
+
+/// First format version which can be encrypted
+const ENCRYPTED_VERSION: u16 = 3;
+/// First format version of which the checksums of toc blocks cover the offset of the next block
+const LINKED_CHECKSUM_VERSION: u16 = 4;
+
+/// Encrypted toc blocks store a key check value and a nonce after their header. These are
+/// followed by the encrypted number of bytes filled (u16) and entries, and the authentication tag.
+const ENCRYPTED_TOC_HEADER_SIZE: usize = TOC_BLOCK_HEADER_SIZE + KEY_CHECK_SIZE + NONCE_SIZE;
+
+/// Starts the header [`PackWriter`] writes before every entry: magic | toc entry | crc32 of
+/// the toc entry. These let the pack be read sequentially by [`PackStreamReader`], although
+/// its table of contents is only written after the data. Readers which seek skip them.
+const ENTRY_MAGIC: &[u8] = b"BPENTRY\0";
+
+/// Toc entries of files, with their attributes
+type Toc = HashMap<String, (TocEntry, Attributes)>;
+
+/// Keys of blobs by the crc32 of their contents, used to find duplicates
+type ContentIndex = HashMap<u32, Vec<(u64, u64)>>;
+
+/// Contents of a file which are loaded into memory
+type Blob = Arc<RwLock<Vec<u8>>>;
+
+/// Contents of changed keys, with the codec they should be compressed with
+type Contents = HashMap<(u64, u64), (Blob, u16)>;
+
+/// An entry in a partially parsed backpack. Entries which are stored in
+/// the backing file are only loaded when they are used, and may be evicted
+/// again when more than `max_allowed_in_memory` bytes are loaded.
 pub struct PartialData {
+    /// physical location of the entry in the backing file
     start: u64,
     end: u64,
 
-    data: Vec<u8>,
+    data: Option<Arc<RwLock<Vec<u8>>>>,
+    /// codec used to compress the entry when it's written
+    codec: u16,
+
+    /// entries that were changed or added since the last flush
+    /// only live in memory, and can't be evicted.
+    dirty: bool,
+    last_used: u64,
+}
+
+/// The headers of a backpack, as read by `parse_headers`.
+/// Backpacks also keep track of the headers written by their
+/// last flush, so later flushes can append to them.
+#[derive(Clone, Default)]
+pub struct Headers {
+    offsets: BTreeMap<String, (u64, u64)>,
+    /// how the contents of each key are stored in the file
+    entries: HashMap<(u64, u64), TocEntry>,
+    attributes: HashMap<String, Attributes>,
+    directories: BTreeSet<String>,
+    toc_blocks: Vec<u64>,
+    data_size: u64,
+    /// format version of the pack
+    version: u16,
+}
+
+/// An entry in the table of contents of a backpack.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TocEntry {
+    offset: u64,
+    /// number of bytes stored in the pack
+    length: u64,
+    codec: u16,
+    /// number of bytes after decompressing
+    size: u64,
+    /// crc32 of the stored bytes
+    checksum: u32,
+    /// nonce the stored bytes were encrypted with, in encrypted packs
+    nonce: Option<Nonce>,
+}
+
+impl TocEntry {
+    /// Size of an entry in a toc block, excluding its name and
+    /// the nonce which follows it in encrypted toc blocks
+    const SIZE: usize = 2 + 8 + 8 + 2 + 8 + 4 + Attributes::SIZE;
+
+    const TOMBSTONE: TocEntry = TocEntry {
+        offset: TOMBSTONE,
+        length: 0,
+        codec: STORE,
+        size: 0,
+        checksum: 0,
+        nonce: None,
+    };
+
+    const DIRECTORY: TocEntry = TocEntry {
+        offset: DIRECTORY,
+        ..Self::TOMBSTONE
+    };
+
+    fn key(&self) -> (u64, u64) {
+        (self.offset, self.length)
+    }
+
+    /// Whether `stored` are the bytes this entry refers to.
+    fn check(&self, stored: &[u8]) -> bool {
+        stored.len() as u64 == self.length && crc32fast::hash(stored) == self.checksum
+    }
+}
+
+impl Headers {
+    /// Whether changes can be appended to the pack. New packs don't have headers to
+    /// append to yet, and packs in older format versions have to be rewritten completely,
+    /// since their toc blocks can't be linked to new ones in the current format.
+    fn can_append(&self) -> bool {
+        !self.toc_blocks.is_empty() && self.version == PACK_VERSION
+    }
+
+    /// Names of all files which refer to `key`, for error messages
+    fn names_of(&self, key: (u64, u64)) -> Vec<String> {
+        let mut res = self.offsets.iter()
+            .filter(|(_, k)| **k == key)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        res.sort_unstable();
+        res
+    }
+}
+
+/// How [`BackPack::flush`] writes changes to the backing file.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Rewrite the entire pack. Space used by removed files is
+    /// reclaimed, but every flush takes time proportional to the
+    /// total size of the pack.
+    #[default]
+    Rewrite,
+
+    /// Only write files which were added or changed since the last
+    /// flush to the end of the pack, followed by a new table of
+    /// contents block which is chained to the previous ones. Removed
+    /// and replaced files keep taking up space until the pack is
+    /// flushed with [`FlushMode::Rewrite`].
+    Append,
 }
 
 pub enum BackPack<'f, 'backpack> {
     PartiallyParsed {
         file: Option<RawFile<'f, 'backpack>>,
-        total_pack_size: u64,
+        /// logical size of the data in the pack. Entries that are
+        /// added are keyed past this offset.
+        total_pack_size: AtomicU64,
         max_allowed_in_memory: usize,
 
-        offsets: HashMap<String, (u64, u64)>,
-        toc_blocks: Vec<u64>,
+        offsets: RwLock<BTreeMap<String, (u64, u64)>>,
+        attributes: RwLock<HashMap<String, Attributes>>,
+        /// directories which were created explicitly. Directories
+        /// containing files exist without being listed here.
+        directories: RwLock<BTreeSet<String>>,
+        flushed: Headers,
+        flush_mode: FlushMode,
+
+        data: Mutex<HashMap<(u64, u64), PartialData>>,
+        accesses: AtomicU64,
+        default_codec: u16,
+        /// only present when deduplication is enabled
+        content_index: Option<Mutex<ContentIndex>>,
+        /// only present when the pack is encrypted
+        cipher: Option<Cipher>,
 
-        data: Vec<PartialData>,
+        closed: bool,
+    },
+    Parsed {
+        file: Option<RawFile<'f, 'backpack>>,
 
-        last_toc_offset: u64,
+        offsets: RwLock<BTreeMap<String, (u64, u64)>>,
+        attributes: RwLock<HashMap<String, Attributes>>,
+        /// directories which were created explicitly. Directories
+        /// containing files exist without being listed here.
+        directories: RwLock<BTreeSet<String>>,
+        backpack: PhantomData<&'backpack ()>,
+        /// contents of the files, which are dropped when no file refers to them anymore
+        data: RwLock<HashMap<(u64, u64), Blob>>,
+        /// codec used to compress each key when it's written
+        codecs: RwLock<HashMap<(u64, u64), u16>>,
+        /// flushed keys which were written to since the last flush
+        modified: Mutex<HashSet<(u64, u64)>>,
+        default_codec: u16,
+        /// only present when deduplication is enabled
+        content_index: Option<Mutex<ContentIndex>>,
+        /// only present when the pack is encrypted
+        cipher: Option<Cipher>,
+
+        /// where the key of the next blob starts, which grows with every blob added
+        total_size: AtomicU64,
+        flushed: Headers,
+        flush_mode: FlushMode,
 
         closed: bool,
     },
-    Parsed {
+    /// A pack which is memory mapped, see [`open_mmap`](BackPack::open_mmap).
+    Mapped {
         file: Option<RawFile<'f, 'backpack>>,
+        map: Mmap,
 
-        offsets: RwLock<HashMap<String, (u64, u64)>>,
-        removals: FrozenMap<String, &'backpack ()>,
-        data: FrozenMap<(u64, u64), Box<RwLock<Vec<u8>>>>,
+        offsets: RwLock<BTreeMap<String, (u64, u64)>>,
+        attributes: RwLock<HashMap<String, Attributes>>,
+        /// directories which were created explicitly. Directories
+        /// containing files exist without being listed here.
+        directories: RwLock<BTreeSet<String>>,
+        flushed: Headers,
 
-        total_size: AtomicU64,
+        /// contents of compressed entries, which can't be used from the map directly
+        decompressed: Mutex<HashMap<(u64, u64), Blob>>,
+        /// entries of which the checksum was checked
+        checked: Mutex<HashSet<(u64, u64)>>,
 
         closed: bool,
     },
 }
 
 impl<'f, 'backpack: 'f> BackPack<'f, 'backpack> {
+    /// Open a backpack stored in a file. Small packs are read into memory
+    /// completely. Packs storing more than [`MAX_ALLOWED_IN_MEMORY`] bytes
+    /// are opened partially (see [`open_partial`](BackPack::open_partial)).
     pub fn open<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<Self> {
-        if false {
-            Self::open_partial(backing)
+        Self::open_with_cipher(backing, None)
+    }
+
+    /// Open an encrypted backpack like [`open`](BackPack::open), with the key it was
+    /// created with (see [`create_encrypted`](BackPack::create_encrypted)). Fails with
+    /// [`PackError::WrongKey`] when `keys` supplies a different key, and with
+    /// [`PackError::NotEncrypted`] when the pack isn't encrypted.
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>, keys: impl KeyProvider) -> error::Result<Self> {
+        Self::open_with_cipher(backing, Some(Cipher::new(&keys)?))
+    }
+
+    /// Same as [`open_partial_with_limit`](BackPack::open_partial_with_limit), for encrypted backpacks.
+    #[cfg(feature = "encryption")]
+    pub fn open_partial_encrypted<E: Into<PackError>>(
+        backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>,
+        keys: impl KeyProvider,
+        max_allowed_in_memory: usize,
+    ) -> error::Result<Self> {
+        let mut file = backing.try_into().map_err(Into::into)?;
+        let cipher = Cipher::new(&keys)?;
+        let headers = Self::parse_headers(&mut file, Some(&cipher))?;
+
+        Self::from_headers_partial(file, headers, max_allowed_in_memory, Some(cipher))
+    }
+
+    fn open_with_cipher<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>, cipher: Option<Cipher>) -> error::Result<Self> {
+        let mut file = backing.try_into().map_err(Into::into)?;
+        let headers = Self::parse_headers(&mut file, cipher.as_ref())?;
+
+        if headers.data_size > MAX_ALLOWED_IN_MEMORY as u64 {
+            Self::from_headers_partial(file, headers, MAX_ALLOWED_IN_MEMORY, cipher)
         } else {
-            Self::open_complete(backing)
+            Self::from_headers_complete(file, headers, cipher)
+        }
+    }
+
+    /// Where the contents of `key` are stored in the backing file, when they can be read from it
+    /// directly: they're stored without compression or encryption, and weren't changed since the
+    /// last flush. Contents of completely parsed packs are in memory already.
+    pub(crate) fn stored_range(&self, key: (u64, u64)) -> Option<(u64, u64)> {
+        let stored_directly = |flushed: &Headers| flushed.entries.get(&key)
+            .filter(|e| e.codec == STORE && e.nonce.is_none())
+            .copied();
+
+        match self {
+            BackPack::PartiallyParsed { data, flushed, .. } => {
+                stored_directly(flushed)?;
+                data.lock().get(&key)
+                    .filter(|e| !e.dirty)
+                    .map(|e| (e.start, e.end))
+            }
+            BackPack::Mapped { flushed, .. } => {
+                let e = stored_directly(flushed)?;
+                let start = Self::convert_offset(&flushed.toc_blocks, e.offset);
+                Some((start, start + e.length))
+            }
+            BackPack::Parsed { .. } => None,
+        }
+    }
+
+    pub(crate) fn backing_file(&self) -> error::Result<&RawFile<'f, 'backpack>> {
+        match self {
+            BackPack::PartiallyParsed { file, .. } |
+            BackPack::Parsed { file, .. } |
+            BackPack::Mapped { file, .. } => file.as_ref().ok_or(Closed),
         }
     }
 
-    pub(crate) fn retrieve_slice(&self, s: &PackSlice) -> &RwLock<Vec<u8>> {
+    /// Get the contents of `key`. In mapped packs these point directly into the map.
+    pub(crate) fn retrieve_bytes(&self, key: (u64, u64)) -> error::Result<MaybeRef<'_, [u8]>> {
         match self {
-            BackPack::PartiallyParsed { .. } => todo!(),
+            BackPack::PartiallyParsed { .. } => {
+                Ok(RwLock::read_arc(&self.load_partial(key, false)?).into())
+            }
+            // contents of removed files are only kept alive by slices of them, see `pin`
             BackPack::Parsed { data, .. } => {
-                data.get(&s.identifier())
-                    .expect("no such file (only packslices obtained from a pack should be used in as_slice)")
+                Ok(data.read().get(&key).map(RwLock::read_arc).ok_or(PackError::InvalidEntry)?.into())
+            }
+            BackPack::Mapped { .. } => self.load_mapped(key),
+        }
+    }
+
+    fn load_mapped(&self, key: (u64, u64)) -> error::Result<MaybeRef<'_, [u8]>> {
+        let (map, flushed, decompressed, checked) = match self {
+            BackPack::Mapped { map, flushed, decompressed, checked, .. } => (map, flushed, decompressed, checked),
+            _ => unreachable!("only mapped backpacks have a map"),
+        };
+
+        let e = flushed.entries.get(&key)
+            .expect("no such file (only packslices obtained from a pack should be used in as_slice)");
+        let start = Self::convert_offset(&flushed.toc_blocks, e.offset);
+        let stored = map.get(start as usize..(start + e.length) as usize)
+            .ok_or(PackError::Truncated(start + e.length, map.len() as u64))?;
+
+        if e.codec == STORE {
+            if !checked.lock().contains(&key) {
+                if !e.check(stored) {
+                    return Err(PackError::Corrupted(flushed.names_of(key)));
+                }
+                checked.lock().insert(key);
+            }
+
+            return Ok(stored.into());
+        }
+
+        let mut decompressed = decompressed.lock();
+        if let Some(data) = decompressed.get(&key) {
+            return Ok(RwLock::read_arc(data).into());
+        }
+
+        if !e.check(stored) {
+            return Err(PackError::Corrupted(flushed.names_of(key)));
+        }
+        let data = Arc::new(RwLock::new(codec::decompress(e.codec, stored.to_vec(), e.size)?));
+        decompressed.insert(key, data.clone());
+
+        Ok(RwLock::read_arc(&data).into())
+    }
+
+    /// Whether the contents of `key` are still stored in the pack
+    fn has_blob(&self, key: (u64, u64)) -> bool {
+        match self {
+            BackPack::PartiallyParsed { data, .. } => data.lock().contains_key(&key),
+            BackPack::Parsed { data, .. } => data.read().contains_key(&key),
+            BackPack::Mapped { flushed, .. } => flushed.entries.contains_key(&key),
+        }
+    }
+
+    /// Find a blob with exactly the same contents, when deduplication is enabled
+    fn find_duplicate(&self, contents: &[u8], hash: u32) -> error::Result<Option<(u64, u64)>> {
+        let candidates = match self {
+            BackPack::PartiallyParsed { content_index: Some(index), .. } |
+            BackPack::Parsed { content_index: Some(index), .. } => {
+                index.lock().get(&hash).cloned().unwrap_or_default()
+            }
+            _ => return Ok(None),
+        };
+
+        for key in candidates {
+            if self.has_blob(key) && &*self.retrieve_bytes(key)? == contents {
+                return Ok(Some(key));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Change the contents of the file `name`, which referred to `key` when it was opened.
+    /// Contents which other files refer to as well are copied first, and `name` is
+    /// changed to refer to the copy. Changed contents are kept in memory until the next
+    /// flush. `pinned` are the contents of `key` kept alive by the handle, see [`pin`](Self::pin).
+    /// Returns the key of the changed contents.
+    pub(crate) fn modify<T>(&self, name: &str, key: (u64, u64), pinned: Option<&Blob>, f: impl FnOnce(&mut Vec<u8>) -> T) -> error::Result<((u64, u64), T)> {
+        if let BackPack::Mapped { .. } = self {
+            return Err(ReadOnly);
+        }
+        // contents of removed files are dropped, unless a handle keeps them alive
+        if pinned.is_none() && !self.has_blob(key) {
+            return Err(PackError::FileNotFound(name.into()));
+        }
+
+        let key = if self.is_shared(name, key) {
+            self.copy_blob(name, key)?
+        } else {
+            key
+        };
+
+        let blob = match self {
+            BackPack::PartiallyParsed { .. } => self.load_partial(key, true)?,
+            BackPack::Parsed { data, modified, .. } => {
+                modified.lock().insert(key);
+                let blob = data.read().get(&key).cloned();
+                blob.or_else(|| pinned.cloned()).ok_or(PackError::InvalidEntry)?
+            }
+            BackPack::Mapped { .. } => unreachable!(),
+        };
+
+        let res = f(&mut blob.write());
+        Ok((key, res))
+    }
+
+    /// Whether files other than `name` refer to `key`
+    fn is_shared(&self, name: &str, key: (u64, u64)) -> bool {
+        self.offsets().read().iter().any(|(n, k)| *k == key && n != name)
+    }
+
+    /// Copy the contents of `key` to a new key, which `name` refers to from now on.
+    fn copy_blob(&self, name: &str, key: (u64, u64)) -> error::Result<(u64, u64)> {
+        let contents = self.retrieve_bytes(key)?.to_vec();
+        let codec = match self {
+            BackPack::PartiallyParsed { data, .. } => data.lock().get(&key).map_or(STORE, |e| e.codec),
+            BackPack::Parsed { codecs, .. } => codecs.read().get(&key).copied().unwrap_or(STORE),
+            BackPack::Mapped { .. } => unreachable!("mapped backpacks can't be changed"),
+        };
+
+        let hash = crc32fast::hash(&contents);
+        let new_key = self.add_blob(contents, codec, hash);
+
+        // handles to files which were replaced since get a private copy
+        if let Some(k) = self.offsets().write().get_mut(name).filter(|k| **k == key) {
+            *k = new_key;
+        }
+
+        Ok(new_key)
+    }
+
+    fn load_partial(&self, key: (u64, u64), dirty: bool) -> error::Result<Arc<RwLock<Vec<u8>>>> {
+        let (file, flushed, data, accesses, max_allowed_in_memory, cipher) = match self {
+            BackPack::PartiallyParsed { file, flushed, data, accesses, max_allowed_in_memory, cipher, .. } => {
+                (file, flushed, data, accesses, *max_allowed_in_memory, cipher)
+            }
+            _ => unreachable!("only partially parsed backpacks load data lazily"),
+        };
+
+        let mut entries = data.lock();
+        let entry = entries.get_mut(&key)
+            .expect("no such file (only packslices obtained from a pack should be used in as_slice)");
+        entry.last_used = accesses.fetch_add(1, Ordering::SeqCst);
+        entry.dirty |= dirty;
+
+        if let Some(ref data) = entry.data {
+            return Ok(data.clone());
+        }
+
+        let buf = Self::read_entry(file.as_ref().ok_or(Closed)?, entry)?;
+        let buf = Self::unstore(flushed, key, buf, cipher.as_ref())?;
+
+        let res = Arc::new(RwLock::new(buf));
+        entry.data = Some(res.clone());
+
+        Self::evict(&mut entries, max_allowed_in_memory);
+
+        Ok(res)
+    }
+
+    fn partial_memory_bytes(entries: &HashMap<(u64, u64), PartialData>) -> usize {
+        entries.values()
+            .filter_map(|e| e.data.as_ref())
+            .map(|d| d.read().len())
+            .sum()
+    }
+
+    /// Evict the least recently used entries until at most `max_allowed_in_memory`
+    /// bytes are loaded. Entries that are modified, or that are currently in use
+    /// are never evicted.
+    fn evict(entries: &mut HashMap<(u64, u64), PartialData>, max_allowed_in_memory: usize) {
+        let mut in_memory = Self::partial_memory_bytes(entries);
+        if in_memory <= max_allowed_in_memory {
+            return;
+        }
+
+        let mut candidates = entries.iter()
+            .filter(|(_, e)| !e.dirty)
+            .filter(|(_, e)| e.data.as_ref().is_some_and(|d| Arc::strong_count(d) == 1))
+            .map(|(key, e)| (e.last_used, *key))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+
+        for (_, key) in candidates {
+            if in_memory <= max_allowed_in_memory {
+                break;
+            }
 
+            if let Some(data) = entries.get_mut(&key).and_then(|e| e.data.take()) {
+                in_memory -= data.read().len();
             }
         }
     }
 
-    fn convert_offset(sorted_toc_block_locations: &Vec<u64>, mut offset: u64) -> u64 {
+    fn convert_offset(sorted_toc_block_locations: &[u64], mut offset: u64) -> u64 {
         offset += PACK_HEADER_SIZE;
 
         for i in sorted_toc_block_locations {
-            if offset <= *i {
+            if *i <= offset {
                 offset += TOC_SIZE as u64;
             }
         }
@@ -79,197 +545,1813 @@ impl<'f, 'backpack: 'f> BackPack<'f, 'backpack> {
         offset
     }
 
-    fn create_toc(offsets: &HashMap<String, (u64, u64)>) -> error::Result<Vec<Vec<u8>>> {
-        if offsets.is_empty() {
+    /// Finish the toc block which will be written at `offset`, as block number `sequence` in
+    /// the chain of toc blocks. Entries of encrypted blocks are bound to where the block is
+    /// and which block it links to (see [`toc_block_aad`](BackPack::toc_block_aad)).
+    fn finish_toc_block(curr: Cursor<Vec<u8>>, offset: u64, next_toc_offset: u64, sequence: u64, cipher: Option<&Cipher>) -> error::Result<Vec<u8>> {
+        let filled = curr.position() as u16;
+        let mut buf = curr.into_inner();
+
+        let filled = match cipher {
+            Some(cipher) => {
+                buf.resize(TOC_SIZE as usize - TAG_SIZE, 0);
+                let entries = &mut buf[ENCRYPTED_TOC_HEADER_SIZE..];
+                entries[..2].copy_from_slice(&(filled - ENCRYPTED_TOC_HEADER_SIZE as u16 - 2).to_le_bytes());
+                let (encrypted, nonce) = cipher.encrypt(entries, &Self::toc_block_aad(offset, next_toc_offset, sequence))?;
+
+                buf.truncate(ENCRYPTED_TOC_HEADER_SIZE);
+                buf[TOC_BLOCK_HEADER_SIZE..TOC_BLOCK_HEADER_SIZE + KEY_CHECK_SIZE].copy_from_slice(cipher.key_check());
+                buf[TOC_BLOCK_HEADER_SIZE + KEY_CHECK_SIZE..].copy_from_slice(&nonce);
+                buf.extend_from_slice(&encrypted);
+                ENCRYPTED_TOC
+            }
+            None => filled,
+        };
+
+        buf[..2].copy_from_slice(&filled.to_le_bytes());
+        buf[2..10].copy_from_slice(&next_toc_offset.to_le_bytes());
+        buf.resize(TOC_SIZE as usize, 0);
+        let checksum = Self::toc_block_checksum(&buf, PACK_VERSION);
+        buf[10..TOC_BLOCK_HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        Ok(buf)
+    }
+
+    /// Encrypted toc blocks authenticate their offset, the offset of the next block and their
+    /// position in the chain. Checksums can be recomputed by anyone, so without this, blocks
+    /// could be moved around, or the chain could be cut short to silently drop changes which
+    /// were appended.
+    fn toc_block_aad(offset: u64, next_toc_offset: u64, sequence: u64) -> [u8; 24] {
+        let mut aad = [0u8; 24];
+        aad[..8].copy_from_slice(&offset.to_le_bytes());
+        aad[8..16].copy_from_slice(&next_toc_offset.to_le_bytes());
+        aad[16..].copy_from_slice(&sequence.to_le_bytes());
+        aad
+    }
+
+    /// The checksum of a toc block covers everything except the checksum itself. Before
+    /// format version 4 it didn't cover the offset of the next block either, which appends
+    /// changed without writing the checksum again.
+    fn toc_block_checksum(block: &[u8], version: u16) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&block[..2]);
+        if version >= LINKED_CHECKSUM_VERSION {
+            hasher.update(&block[2..10]);
+        }
+        hasher.update(&block[TOC_BLOCK_HEADER_SIZE..]);
+        hasher.finalize()
+    }
+
+    /// Create the toc blocks for a set of entries, which will be written consecutively to
+    /// the file starting at `first_toc_offset`, and continue the chain at `first_sequence`.
+    fn create_toc(entries: &Toc, first_toc_offset: u64, first_sequence: u64, cipher: Option<&Cipher>) -> error::Result<Vec<Vec<u8>>> {
+        // encrypted packs always have a toc block, so unlinking all of them is noticed
+        if entries.is_empty() && cipher.is_none() {
             return Ok(Vec::new());
         }
 
+        // part of the block where entries are written
+        let (start, end, nonce_size) = match cipher {
+            Some(_) => (ENCRYPTED_TOC_HEADER_SIZE + 2, TOC_SIZE as usize - TAG_SIZE, NONCE_SIZE),
+            None => (TOC_BLOCK_HEADER_SIZE, TOC_SIZE as usize, 0),
+        };
+
         let mut res = Vec::new();
         let mut curr = Cursor::new(Vec::new());
-        let ten_zeros: [u8; 10] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        curr.write(&ten_zeros)?;
+        curr.write_all(&vec![0; start])?;
 
-        let mut offsets = offsets.into_iter().collect::<Vec<_>>();
-        offsets.sort_by_key(|(_, (i, _))| i);
+        let mut entries = entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, (e, _))| e.offset);
 
-        for (s, (offset, length)) in offsets {
-            let entry_size = 8 + 2 + 2 + s.bytes().len();
-            let filled = curr.seek(SeekFrom::Current(0))?;
+        for (s, (e, attributes)) in entries {
+            let entry_size = TocEntry::SIZE + s.len() + nonce_size;
+            if entry_size + start > end {
+                return Err(PackError::NameTooLong(s.clone()));
+            }
 
-            if filled + entry_size as u64 > TOC_SIZE as u64 {
-                curr.seek(SeekFrom::Start(0))?;
-                curr.write(&(filled as u16).to_le_bytes())?;
-                curr.write(&(TOC_SIZE as u64).to_le_bytes())?;
+            if curr.position() + entry_size as u64 > end as u64 {
+                let offset = first_toc_offset + res.len() as u64 * TOC_SIZE as u64;
+                let sequence = first_sequence + res.len() as u64;
+                res.push(Self::finish_toc_block(curr, offset, offset + TOC_SIZE as u64, sequence, cipher)?);
 
-                let mut buf = curr.into_inner();
-                buf.resize(TOC_SIZE as usize, 0);
-                res.push(buf);
                 curr = Cursor::new(Vec::new());
-                curr.write(&ten_zeros)?;
-            } else {
-                curr.write(&(s.as_bytes().len() as u16).to_le_bytes())?;
-                curr.write(s.as_bytes())?;
-                curr.write(&offset.to_le_bytes())?;
-                curr.write(&length.to_le_bytes())?;
+                curr.write_all(&vec![0; start])?;
             }
-        }
-
-        let filled = curr.seek(SeekFrom::Current(0))?;
-        curr.seek(SeekFrom::Start(0))?;
-        curr.write(&(filled as u16).to_le_bytes())?;
 
-        let mut buf = curr.into_inner();
-        buf.resize(TOC_SIZE as usize, 0);
-        res.push(buf);
+            Self::write_toc_entry(&mut curr, s, e, attributes, cipher.is_some())?;
+        }
 
+        let offset = first_toc_offset + res.len() as u64 * TOC_SIZE as u64;
+        let sequence = first_sequence + res.len() as u64;
+        res.push(Self::finish_toc_block(curr, offset, 0, sequence, cipher)?);
 
         Ok(res)
     }
 
-    fn write_headers(f: &mut RawFile, size: u64, offsets: &HashMap<String, (u64, u64)>) -> error::Result<()> {
-        let toc_blocks = Self::create_toc(offsets)?;
+    /// Toc entries of explicitly created directories
+    fn directory_toc<'a>(
+        directories: &'a BTreeSet<String>,
+        attributes: &'a HashMap<String, Attributes>,
+    ) -> impl Iterator<Item=(String, (TocEntry, Attributes))> + 'a {
+        directories.iter()
+            .map(|name| (name.clone(), (TocEntry::DIRECTORY, attributes.get(name).copied().unwrap_or_default())))
+    }
 
-        f.write(PACK_MAGIC)?;
-        f.write(&PACK_VERSION.to_le_bytes())?;
-        f.write(&size.to_le_bytes())?;
+    fn write_headers(f: &mut RawFile, size: u64, toc_blocks: &[Vec<u8>]) -> error::Result<()> {
+        f.write_all(PACK_MAGIC)?;
+        f.write_all(&PACK_VERSION.to_le_bytes())?;
+        f.write_all(&size.to_le_bytes())?;
         if toc_blocks.is_empty() {
-            f.write(&0u64.to_le_bytes())?;
+            f.write_all(&0u64.to_le_bytes())?;
         } else {
-            f.write(&(PACK_HEADER_SIZE as u64).to_le_bytes())?;
+            f.write_all(&PACK_HEADER_SIZE.to_le_bytes())?;
         }
         for i in toc_blocks {
-            f.write(&i)?;
+            f.write_all(i)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_toc_entry(out: &mut impl Write, name: &str, e: &TocEntry, attributes: &Attributes, encrypted: bool) -> std::io::Result<()> {
+        out.write_all(&(name.len() as u16).to_le_bytes())?;
+        out.write_all(name.as_bytes())?;
+        out.write_all(&e.offset.to_le_bytes())?;
+        out.write_all(&e.length.to_le_bytes())?;
+        out.write_all(&e.codec.to_le_bytes())?;
+        out.write_all(&e.size.to_le_bytes())?;
+        out.write_all(&e.checksum.to_le_bytes())?;
+        out.write_all(&attributes.to_bytes())?;
+        if encrypted {
+            out.write_all(&e.nonce.unwrap_or_default())?;
         }
 
         Ok(())
     }
 
-    fn parse_toc_block(filled: u16, block: &[u8], offsets: &mut HashMap<String, (u64, u64)>) -> error::Result<()> {
+    /// The header which precedes an entry in packs written by [`PackWriter`], see [`ENTRY_MAGIC`]
+    fn inline_header(name: &str, e: &TocEntry, attributes: &Attributes) -> std::io::Result<Vec<u8>> {
+        let mut header = ENTRY_MAGIC.to_vec();
+        Self::write_toc_entry(&mut header, name, e, attributes, false)?;
+        let checksum = crc32fast::hash(&header[ENTRY_MAGIC.len()..]);
+        header.extend_from_slice(&checksum.to_le_bytes());
+
+        Ok(header)
+    }
+
+    /// Parse the entries of the toc block at `toc_offset` into `entries`.
+    fn parse_toc_block(block: &[u8], toc_offset: u64, encrypted: bool, entries: &mut Toc) -> error::Result<()> {
         let mut curr: usize = 0;
-        while (curr as u16) < filled {
-            let mut strlen_bytes = [0u8; 2];
-            strlen_bytes.copy_from_slice(&block[curr..curr+2]);
-            curr += 2;
-            let strlen = u16::from_le_bytes(strlen_bytes);
+        while curr < block.len() {
+            let (name, e, attributes) = Self::parse_toc_entry(block, toc_offset, &mut curr, encrypted)?;
+            if e.offset == TOMBSTONE {
+                entries.remove(&name);
+            } else {
+                entries.insert(name, (e, attributes));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse the toc entry at `curr` in `block`, which is stored at `toc_offset`, and move `curr`
+    /// past it. Names are normalized, names which can't be are rejected.
+    fn parse_toc_entry(block: &[u8], toc_offset: u64, curr: &mut usize, encrypted: bool) -> error::Result<(String, TocEntry, Attributes)> {
+        let mut take = |n: usize| {
+            let bytes = block.get(*curr..*curr + n).ok_or(PackError::CorruptedToc(toc_offset))?;
+            *curr += n;
+            Ok::<_, PackError>(bytes)
+        };
+
+        let mut strlen_bytes = [0u8; 2];
+        strlen_bytes.copy_from_slice(take(2)?);
+        let strlen = u16::from_le_bytes(strlen_bytes);
+
+        let string = take(strlen as usize)?.to_vec();
+
+        let mut offset_bytes = [0u8; 8];
+        offset_bytes.copy_from_slice(take(8)?);
+        let offset = u64::from_le_bytes(offset_bytes);
+
+        let mut length_bytes = [0u8; 8];
+        length_bytes.copy_from_slice(take(8)?);
+        let length = u64::from_le_bytes(length_bytes);
+
+        let mut codec_bytes = [0u8; 2];
+        codec_bytes.copy_from_slice(take(2)?);
+        let codec = u16::from_le_bytes(codec_bytes);
+
+        let mut size_bytes = [0u8; 8];
+        size_bytes.copy_from_slice(take(8)?);
+        let size = u64::from_le_bytes(size_bytes);
+
+        let mut checksum_bytes = [0u8; 4];
+        checksum_bytes.copy_from_slice(take(4)?);
+        let checksum = u32::from_le_bytes(checksum_bytes);
+
+        let attributes = Attributes::from_bytes(take(Attributes::SIZE)?);
+
+        let nonce = if encrypted {
+            let mut nonce = [0u8; NONCE_SIZE];
+            nonce.copy_from_slice(take(NONCE_SIZE)?);
+            Some(nonce)
+        } else {
+            None
+        };
+
+        let string = normalize_entry(Path::new(&String::from_utf8(string)?))?;
+        Ok((string, TocEntry { offset, length, codec, size, checksum, nonce }, attributes))
+    }
+
+    /// Read the toc block at `offset`, which is block number `sequence` in the chain of a pack in
+    /// format `version`, checking its checksum and decrypting it in encrypted packs. Returns the
+    /// offset of the next toc block, and the entries in the block.
+    fn read_toc_block(file: &RawFile, offset: u64, sequence: u64, version: u16, cipher: Option<&Cipher>) -> error::Result<(u64, Vec<u8>)> {
+        let mut block = vec![0; TOC_SIZE as usize];
+        match file.read_exact_at(&mut block, offset) {
+            Err(PackError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(Self::damaged_toc(offset, cipher));
+            }
+            res => res?,
+        }
+
+        Self::decode_toc_block(&block, offset, sequence, version, cipher)
+    }
+
+    /// Read the chain of toc blocks starting at `first_toc_offset`, returning the offset and the
+    /// entries of every block. Chains which loop, or which have more blocks than fit in the
+    /// file, are damaged.
+    fn read_toc_chain(file: &RawFile, first_toc_offset: u64, version: u16, cipher: Option<&Cipher>) -> error::Result<Vec<(u64, Vec<u8>)>> {
+        let max_blocks = file.metadata()?.len() / TOC_SIZE as u64;
+        let mut visited = HashSet::new();
+        let mut res = Vec::new();
+
+        let mut next_toc_offset = first_toc_offset;
+        while next_toc_offset != 0 {
+            if !visited.insert(next_toc_offset) || res.len() as u64 >= max_blocks {
+                return Err(Self::damaged_toc(next_toc_offset, cipher));
+            }
+
+            let (next, entries) = Self::read_toc_block(file, next_toc_offset, res.len() as u64, version, cipher)?;
+            res.push((next_toc_offset, entries));
+            next_toc_offset = next;
+        }
+
+        Ok(res)
+    }
+
+    /// Check and decrypt the toc block which was read from `offset`,
+    /// see [`read_toc_block`](BackPack::read_toc_block).
+    fn decode_toc_block(block: &[u8], offset: u64, sequence: u64, version: u16, cipher: Option<&Cipher>) -> error::Result<(u64, Vec<u8>)> {
+        let damaged = Self::damaged_toc(offset, cipher);
+
+        let mut checksum_bytes = [0u8; 4];
+        checksum_bytes.copy_from_slice(&block[10..TOC_BLOCK_HEADER_SIZE]);
+        if u32::from_le_bytes(checksum_bytes) != Self::toc_block_checksum(block, version) {
+            return Err(damaged);
+        }
+
+        let mut filled_bytes = [0u8; 2];
+        filled_bytes.copy_from_slice(&block[..2]);
+        let filled = u16::from_le_bytes(filled_bytes);
+
+        let mut next_toc_bytes = [0u8; 8];
+        next_toc_bytes.copy_from_slice(&block[2..10]);
+        let next_toc_offset = u64::from_le_bytes(next_toc_bytes);
+
+        let entries = match (filled & ENCRYPTED_TOC != 0, cipher) {
+            (false, None) => {
+                if !(TOC_BLOCK_HEADER_SIZE..=TOC_SIZE as usize).contains(&(filled as usize)) {
+                    return Err(damaged);
+                }
+                block[TOC_BLOCK_HEADER_SIZE..filled as usize].to_vec()
+            }
+            (true, Some(cipher)) => {
+                if block[TOC_BLOCK_HEADER_SIZE..TOC_BLOCK_HEADER_SIZE + KEY_CHECK_SIZE] != cipher.key_check()[..] {
+                    return Err(PackError::WrongKey);
+                }
+
+                let mut nonce = [0u8; NONCE_SIZE];
+                nonce.copy_from_slice(&block[TOC_BLOCK_HEADER_SIZE + KEY_CHECK_SIZE..ENCRYPTED_TOC_HEADER_SIZE]);
+                let mut entries = cipher.decrypt(&nonce, &block[ENCRYPTED_TOC_HEADER_SIZE..], &Self::toc_block_aad(offset, next_toc_offset, sequence))
+                    .ok_or(damaged)?;
+
+                filled_bytes.copy_from_slice(&entries[..2]);
+                let filled = u16::from_le_bytes(filled_bytes) as usize;
+                entries.truncate(2 + filled);
+                entries.drain(..2);
+                entries
+            }
+            (true, None) => return Err(PackError::KeyRequired),
+            // every block of an encrypted pack is encrypted
+            (false, Some(_)) => return Err(PackError::NotEncrypted),
+        };
+
+        Ok((next_toc_offset, entries))
+    }
+
+    /// Damage to encrypted packs can't be told apart from tampering
+    fn damaged_toc(offset: u64, cipher: Option<&Cipher>) -> PackError {
+        match cipher {
+            Some(_) => PackError::TamperedToc(offset),
+            None => PackError::CorruptedToc(offset),
+        }
+    }
+
+    fn parse_backwards_compatible(file: &mut RawFile, version: u16, cipher: Option<&Cipher>) -> error::Result<Headers> {
+        match version {
+            0 if cipher.is_some() => Err(PackError::NotEncrypted),
+            0 => Self::parse_v0(file),
+            _ => Err(PackError::Incompatible(version)),
+        }
+    }
+
+    /// Reads packs in format version 0, which was written by all releases before the format
+    /// version was separated from the version of the crate. These have a single table of contents
+    /// (in consecutive blocks) followed by the data of all files, without compression or
+    /// checksums. Checksums are computed while parsing, so they can be written when the pack
+    /// is rewritten in the current format.
+    fn parse_v0(file: &mut RawFile) -> error::Result<Headers> {
+        let mut size_bytes = [0u8; 8];
+        file.read_exact(&mut size_bytes)?;
+        let data_size = u64::from_le_bytes(size_bytes);
+
+        let mut first_toc_offset_bytes = [0u8; 8];
+        file.read_exact(&mut first_toc_offset_bytes)?;
+        let first_toc_offset = u64::from_le_bytes(first_toc_offset_bytes);
+
+        let mut offsets = BTreeMap::new();
+        let mut toc_blocks = Vec::new();
+
+        // the offset of the next toc block wasn't written correctly,
+        // but it's only zero for the last block.
+        let mut has_next = first_toc_offset != 0;
+        while has_next {
+            let toc_offset = PACK_HEADER_SIZE + toc_blocks.len() as u64 * TOC_SIZE as u64;
+            toc_blocks.push(toc_offset);
+
+            let mut block = vec![0; TOC_SIZE as usize];
+            match file.read_exact_at(&mut block, toc_offset) {
+                Err(PackError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Err(PackError::CorruptedToc(toc_offset));
+                }
+                res => res?,
+            }
+
+            let mut filled_bytes = [0u8; 2];
+            filled_bytes.copy_from_slice(&block[..2]);
+            let filled = u16::from_le_bytes(filled_bytes) as usize;
+
+            let mut next_toc_bytes = [0u8; 8];
+            next_toc_bytes.copy_from_slice(&block[2..10]);
+            has_next = u64::from_le_bytes(next_toc_bytes) != 0;
+
+            if !(10..=TOC_SIZE as usize).contains(&filled) {
+                return Err(PackError::CorruptedToc(toc_offset));
+            }
+
+            let block = &block[..filled];
+            let mut curr = 10;
+            while curr < filled {
+                let mut take = |n: usize| {
+                    let bytes = block.get(curr..curr + n).ok_or(PackError::CorruptedToc(toc_offset))?;
+                    curr += n;
+                    Ok::<_, PackError>(bytes)
+                };
+
+                let mut strlen_bytes = [0u8; 2];
+                strlen_bytes.copy_from_slice(take(2)?);
+                let strlen = u16::from_le_bytes(strlen_bytes) as usize;
+
+                let name = normalize_entry(Path::new(&String::from_utf8(take(strlen)?.to_vec())?))?;
+
+                let mut offset_bytes = [0u8; 8];
+                offset_bytes.copy_from_slice(take(8)?);
+
+                let mut length_bytes = [0u8; 8];
+                length_bytes.copy_from_slice(take(8)?);
+
+                offsets.insert(name, (u64::from_le_bytes(offset_bytes), u64::from_le_bytes(length_bytes)));
+            }
+        }
+
+        let data_start = PACK_HEADER_SIZE + toc_blocks.len() as u64 * TOC_SIZE as u64;
+        let file_size = file.seek(SeekFrom::End(0))?;
+        if file_size < data_start + data_size {
+            return Err(PackError::Truncated(data_start + data_size, file_size));
+        }
+
+        let mut entries = HashMap::new();
+        for &(offset, length) in offsets.values() {
+            let mut buf = vec![0; length as usize];
+            file.read_exact_at(&mut buf, data_start + offset)?;
+
+            entries.insert((offset, length), TocEntry {
+                offset,
+                length,
+                codec: STORE,
+                size: length,
+                checksum: crc32fast::hash(&buf),
+                nonce: None,
+            });
+        }
+
+        Ok(Headers {
+            offsets,
+            entries,
+            attributes: HashMap::new(),
+            directories: BTreeSet::new(),
+            toc_blocks,
+            data_size,
+            version: 0,
+        })
+    }
+
+    fn parse_headers(file: &mut RawFile, cipher: Option<&Cipher>) -> error::Result<Headers> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut magic_bytes = [0u8; PACK_MAGIC.len()];
+        file.read_exact(&mut magic_bytes)?;
+        if magic_bytes != PACK_MAGIC {
+            return Err(PackError::BadMagic);
+        }
+
+        let mut version_bytes = [0u8; 2];
+        file.read_exact(&mut version_bytes)?;
+        let version = u16::from_le_bytes(version_bytes);
+        // later versions only add kinds of toc entries and blocks, which are flagged in the toc itself
+        if !(1..=PACK_VERSION).contains(&version) {
+            return Self::parse_backwards_compatible(file, version, cipher);
+        }
+        if cipher.is_some() && version < ENCRYPTED_VERSION {
+            return Err(PackError::NotEncrypted);
+        }
+
+        let mut size_bytes = [0u8; 8];
+        file.read_exact(&mut size_bytes)?;
+        let data_size = u64::from_le_bytes(size_bytes);
 
-            let mut string = Vec::new();
-            string.extend_from_slice(&block[curr..curr + strlen as usize]);
-            curr += strlen as usize;
+        let mut first_toc_offset_bytes = [0u8; 8];
+        file.read_exact(&mut first_toc_offset_bytes)?;
+        let first_toc_offset = u64::from_le_bytes(first_toc_offset_bytes);
+
+        assert_eq!(file.current_offset()?, PACK_HEADER_SIZE);
+
+        let mut toc_entries = HashMap::new();
+        let mut toc_blocks = Vec::new();
+
+        // encrypted packs always have a toc block, see create_toc
+        if cipher.is_some() && first_toc_offset == 0 {
+            return Err(PackError::TamperedHeader);
+        }
+
+        for (offset, entries) in Self::read_toc_chain(file, first_toc_offset, version, cipher)? {
+            toc_blocks.push(offset);
+            Self::parse_toc_block(&entries, offset, cipher.is_some(), &mut toc_entries)?;
+        }
+
+        toc_blocks.sort_unstable();
+
+        let expected_size = PACK_HEADER_SIZE + data_size + toc_blocks.len() as u64 * TOC_SIZE as u64;
+        let file_size = file.seek(SeekFrom::End(0))?;
+        if file_size < expected_size {
+            return Err(PackError::Truncated(expected_size, file_size));
+        }
+
+        let (directories, files): (Toc, Toc) = toc_entries.into_iter()
+            .partition(|(_, (e, _))| e.offset == DIRECTORY);
+
+        // unlike the toc blocks, the header isn't authenticated. The data has to fit in the
+        // data size it gives, and the toc blocks have to end where the pack does.
+        if cipher.is_some() {
+            let end = PACK_HEADER_SIZE + data_size + toc_blocks.len() as u64 * TOC_SIZE as u64;
+            let misplaced_toc = toc_blocks.last().is_some_and(|last| last + TOC_SIZE as u64 > end);
+            let misplaced_data = files.values().any(|(e, _)| e.offset.saturating_add(e.length) > data_size);
+            if misplaced_toc || misplaced_data {
+                return Err(PackError::TamperedHeader);
+            }
+        }
+
+        Ok(Headers {
+            offsets: files.iter().map(|(name, (e, _))| (name.clone(), e.key())).collect(),
+            attributes: files.iter().chain(&directories).map(|(name, (_, a))| (name.clone(), *a)).collect(),
+            directories: directories.into_keys().collect(),
+            entries: files.into_values().map(|(e, _)| (e.key(), e)).collect(),
+            toc_blocks,
+            data_size,
+            version,
+        })
+    }
+
+    /// Rewrite a pack in an older format version in the current format (see [`PACK_VERSION`]).
+    /// Packs in older format versions can be opened like any other pack, but changes to them
+    /// can't be appended.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    /// # use backpack::pack::PACK_VERSION;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    /// #   let file = BackPack::create(RawFile::in_memory("old.bp"))?.close()?;
+    ///     let file = BackPack::upgrade(file)?;
+    ///
+    ///     let bp = BackPack::open(file)?;
+    ///     assert_eq!(bp.version(), PACK_VERSION);
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn upgrade<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<RawFile<'f, 'backpack>> {
+        let mut bp = Self::open(backing)?;
+        bp.set_flush_mode(FlushMode::Rewrite);
+        bp.close()
+    }
+
+    /// The format version of the pack, as it was last opened or flushed.
+    pub fn version(&self) -> u16 {
+        self.flushed().version
+    }
+
+    /// Size of the file contents stored in the pack, as it was last opened or flushed.
+    pub fn data_size(&self) -> u64 {
+        self.flushed().data_size
+    }
+
+    /// Number of table of contents blocks in the pack, as it was last opened or flushed.
+    pub fn toc_block_count(&self) -> usize {
+        self.flushed().toc_blocks.len()
+    }
+
+    fn flushed(&self) -> &Headers {
+        match self {
+            BackPack::PartiallyParsed { flushed, .. } |
+            BackPack::Parsed { flushed, .. } |
+            BackPack::Mapped { flushed, .. } => flushed,
+        }
+    }
+
+    /// Open a backpack, reading all of its contents into memory.
+    pub fn open_complete<E: Into<PackError>>(file: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<Self> {
+        let mut file = file.try_into().map_err(Into::into)?;
+        let headers = Self::parse_headers(&mut file, None)?;
+
+        Self::from_headers_complete(file, headers, None)
+    }
+
+    fn from_headers_complete(mut file: RawFile<'f, 'backpack>, headers: Headers, cipher: Option<Cipher>) -> error::Result<Self> {
+        let mut data = HashMap::new();
+        let mut codecs = HashMap::new();
+
+        for (key, e) in &headers.entries {
+            let new_offset = Self::convert_offset(&headers.toc_blocks, e.offset);
+            file.seek(SeekFrom::Start(new_offset))?;
+
+            let mut buf = vec![0; e.length as usize];
+            file.read_exact(&mut buf)?;
+            let buf = Self::unstore(&headers, *key, buf, cipher.as_ref())?;
+
+            data.insert(*key, Arc::new(RwLock::new(buf)));
+            codecs.insert(*key, e.codec);
+        }
+
+
+        Ok(Self::Parsed {
+            file: Some(file),
+            offsets: RwLock::new(headers.offsets.clone()),
+            attributes: RwLock::new(headers.attributes.clone()),
+            directories: RwLock::new(headers.directories.clone()),
+            backpack: PhantomData,
+            data: RwLock::new(data),
+            codecs: RwLock::new(codecs),
+            modified: Default::default(),
+            default_codec: STORE,
+            content_index: None,
+            cipher,
+
+            // after appending, the pack may contain data of removed
+            // files. Keys of new files must not overlap with it.
+            total_size: AtomicU64::new(headers.data_size),
+            flushed: headers,
+            flush_mode: FlushMode::default(),
+
+            // not closed
+            closed: false
+        })
+    }
+
+    /// Open a backpack, only parsing its headers and table of contents. The
+    /// contents of files are read from the backing file when they are used,
+    /// and [`MAX_ALLOWED_IN_MEMORY`] bytes of them are kept in memory.
+    pub fn open_partial<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<Self> {
+        Self::open_partial_with_limit(backing, MAX_ALLOWED_IN_MEMORY)
+    }
+
+    /// Same as [`open_partial`](BackPack::open_partial), but keeps at most
+    /// `max_allowed_in_memory` bytes of file contents in memory. Files which
+    /// were added or modified since the last flush can't be evicted, and
+    /// are not limited by this.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_named("some large file", "large.txt")?;
+    ///     let file = bp.close()?;
+    ///
+    ///     let bp = BackPack::open_partial_with_limit(file, 1024)?;
+    ///     assert_eq!(bp.memory_bytes(), 0);
+    ///     assert_eq!(&*bp.get_file("large.txt")?.get_bytes(), b"some large file");
+    ///
+    ///     bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn open_partial_with_limit<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>, max_allowed_in_memory: usize) -> error::Result<Self> {
+        let mut file = backing.try_into().map_err(Into::into)?;
+        let headers = Self::parse_headers(&mut file, None)?;
+
+        Self::from_headers_partial(file, headers, max_allowed_in_memory, None)
+    }
+
+    fn from_headers_partial(file: RawFile<'f, 'backpack>, headers: Headers, max_allowed_in_memory: usize, cipher: Option<Cipher>) -> error::Result<Self> {
+        let data = headers.entries.iter()
+            .map(|(key, e)| {
+                let start = Self::convert_offset(&headers.toc_blocks, e.offset);
+
+                (*key, PartialData {
+                    start,
+                    end: start + e.length,
+                    data: None,
+                    codec: e.codec,
+                    dirty: false,
+                    last_used: 0,
+                })
+            })
+            .collect();
+
+        Ok(Self::PartiallyParsed {
+            file: Some(file),
+            total_pack_size: AtomicU64::new(headers.data_size),
+            max_allowed_in_memory,
+            offsets: RwLock::new(headers.offsets.clone()),
+            attributes: RwLock::new(headers.attributes.clone()),
+            directories: RwLock::new(headers.directories.clone()),
+            flushed: headers,
+            flush_mode: FlushMode::default(),
+            data: Mutex::new(data),
+            accesses: AtomicU64::new(0),
+            default_codec: STORE,
+            content_index: None,
+            cipher,
+
+            // not closed
+            closed: false,
+        })
+    }
+
+    /// Open a backpack by memory mapping it. Files stored without compression
+    /// are used directly from the map, without copying them into memory.
+    /// Mapped packs can't be changed: adding or removing files fails with
+    /// [`PackError::ReadOnly`].
+    ///
+    /// The pack must not be changed (by this or another process) while it's mapped.
+    /// Encrypted packs can't be mapped, since their contents can't be used directly.
+    pub fn open_mmap(path: impl AsRef<Path>) -> error::Result<Self> {
+        let mut file = RawFile::open(path)?;
+        let headers = Self::parse_headers(&mut file, None)?;
+
+        let map = match &file {
+            // SAFETY: it's up to the caller not to change the pack while it's mapped
+            RawFile::Disk { file, .. } => unsafe { Mmap::map(file)? },
+            RawFile::InMemory(..) => unreachable!("RawFile::open opens files on disk"),
+        };
+
+        Ok(Self::Mapped {
+            file: Some(file),
+            map,
+            offsets: RwLock::new(headers.offsets.clone()),
+            attributes: RwLock::new(headers.attributes.clone()),
+            directories: RwLock::new(headers.directories.clone()),
+            flushed: headers,
+            decompressed: Default::default(),
+            checked: Default::default(),
+            closed: false,
+        })
+    }
+
+    /// Create a new pack in a file. Usually called after File::create().
+    /// Existing contents of the file are deleted.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let file = RawFile::in_memory("test.bp");
+    ///     let bp = BackPack::create(file)?;
+    ///
+    ///     // when done with it
+    ///     bp.close();
+    /// #   Ok(())
+    /// # }
+    /// ```
+    ///
+    pub fn create<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<Self> {
+        Self::create_with_cipher(backing, None)
+    }
+
+    /// Create a new pack like [`create`](BackPack::create), which is encrypted with the key
+    /// supplied by `keys`. The contents of files are encrypted in chunks with a random nonce,
+    /// which is stored in the table of contents. The table of contents is encrypted as well, so the
+    /// names and sizes of files can't be read without the key either.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let key = [42; 32];
+    ///     let bp = BackPack::create_encrypted(RawFile::in_memory("test.bp"), key)?;
+    ///     bp.add_file_named("secret", "test.txt")?;
+    ///     let file = bp.close()?;
+    ///
+    ///     let bp = BackPack::open_encrypted(file, key)?;
+    ///     assert_eq!(&*bp.get_file("test.txt")?.get_bytes(), b"secret");
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "encryption")]
+    pub fn create_encrypted<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>, keys: impl KeyProvider) -> error::Result<Self> {
+        Self::create_with_cipher(backing, Some(Cipher::new(&keys)?))
+    }
+
+    fn create_with_cipher<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>, cipher: Option<Cipher>) -> error::Result<Self> {
+        let mut file = backing.try_into().map_err(Into::into)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(Self::Parsed {
+            file: Some(file),
+            offsets: Default::default(),
+            attributes: Default::default(),
+            directories: Default::default(),
+            backpack: PhantomData,
+            data: Default::default(),
+            codecs: Default::default(),
+            modified: Default::default(),
+            default_codec: STORE,
+            content_index: None,
+            cipher,
+            total_size: AtomicU64::new(0),
+            flushed: Headers::default(),
+            flush_mode: FlushMode::default(),
+            // not closed
+            closed: false,
+        })
+    }
+
+    /// Gets the number of bytes of file contents which are held in memory currently,
+    /// after decompressing them. If packs get really large (contain lots of files)
+    /// you might want to flush
+    pub fn memory_bytes(&self) -> usize {
+        match self {
+            BackPack::PartiallyParsed { data, .. } => {
+                Self::partial_memory_bytes(&data.lock())
+            },
+            BackPack::Parsed { data, .. } => {
+                data.read().values().map(|d| d.read().len()).sum()
+            },
+            // stored files aren't copied out of the map
+            BackPack::Mapped { decompressed, .. } => {
+                decompressed.lock().values().map(|d| d.read().len()).sum()
+            }
+        }
+    }
+
+    /// Check the integrity of everything which was flushed to the backing file.
+    /// Changes which aren't flushed yet are not checked. All corrupted entries
+    /// are reported at once in [`PackError::Corrupted`] (or [`PackError::Tampered`]
+    /// for encrypted packs). Since opening a pack
+    /// completely fails on the first corrupted entry, damaged packs should be
+    /// opened with [`open_partial`](BackPack::open_partial) to verify them.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_named("hello", "test.txt")?;
+    ///     let file = bp.close()?;
+    ///
+    ///     let bp = BackPack::open_partial(file)?;
+    ///     bp.verify()?;
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn verify(&self) -> error::Result<()> {
+        let (file, flushed) = match self {
+            BackPack::PartiallyParsed { file, flushed, .. } |
+            BackPack::Parsed { file, flushed, .. } |
+            BackPack::Mapped { file, flushed, .. } => (file.as_ref().ok_or(Closed)?, flushed),
+        };
+        let cipher = self.cipher();
+
+        // toc blocks of format version 0 don't have checksums
+        if flushed.version >= 1 {
+            let mut first_toc_offset_bytes = [0u8; 8];
+            file.read_exact_at(&mut first_toc_offset_bytes, FIRST_TOC_OFFSET_POSITION)?;
+            let first_toc_offset = u64::from_le_bytes(first_toc_offset_bytes);
+
+            // toc blocks are only ever added after the existing ones, so they're sorted in chain order
+            let chain = Self::read_toc_chain(file, first_toc_offset, flushed.version, cipher)?;
+            if !chain.iter().map(|(offset, _)| offset).eq(&flushed.toc_blocks) {
+                return Err(Self::damaged_toc(first_toc_offset, cipher));
+            }
+        }
+
+        let mut corrupted = Vec::new();
+        for (key, e) in &flushed.entries {
+            let mut buf = vec![0; e.length as usize];
+            let start = Self::convert_offset(&flushed.toc_blocks, e.offset);
+            let intact = match file.read_exact_at(&mut buf, start) {
+                Ok(()) => Self::unstore(flushed, *key, buf, cipher).is_ok(),
+                Err(PackError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => false,
+                Err(e) => return Err(e),
+            };
+
+            if !intact {
+                corrupted.extend(flushed.names_of(*key));
+            }
+        }
+
+        corrupted.sort_unstable();
+        match (corrupted.is_empty(), cipher) {
+            (true, _) => Ok(()),
+            (false, Some(_)) => Err(PackError::Tampered(corrupted)),
+            (false, None) => Err(PackError::Corrupted(corrupted)),
+        }
+    }
+
+    /// Write all changes since the last flush to the file
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::pack::BackPack;
+    /// # use backpack::pack::PackError;
+    /// # use std::io::Read;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let file = RawFile::in_memory("test.bp");
+    ///     let bp = BackPack::create(file)?;
+    ///
+    ///     // when done with it
+    ///     let mut file = bp.close()?;
+    ///     // now there's stuff in the file, which can be read again with `open`
+    ///     let bp = BackPack::open(file)?;
+    ///
+    /// #   Ok(())
+    /// # }
+    ///
+    /// ```
+    pub fn flush(&mut self) -> error::Result<()> {
+        match self {
+            BackPack::PartiallyParsed {
+                file,
+                offsets,
+                attributes,
+                directories,
+                flushed,
+                flush_mode,
+                data,
+                cipher,
+                ..
+            } => {
+                let file = file.as_mut().ok_or(Closed)?;
+                let offsets = offsets.get_mut();
+                let attributes = attributes.get_mut();
+                let directories = directories.get_mut();
+                let entries = data.get_mut();
+
+                // contents of removed files are kept while handles to them may be open
+                let referenced = offsets.values().collect::<HashSet<_>>();
+                entries.retain(|key, _| referenced.contains(key));
+
+                if *flush_mode == FlushMode::Rewrite || !flushed.can_append() {
+                    return Self::flush_partial(file, offsets, attributes, directories, flushed, entries, cipher.as_ref());
+                }
+
+                let mut contents = HashMap::new();
+                for key in offsets.values() {
+                    let e = entries.get(key).ok_or(PackError::InvalidEntry)?;
+                    if let (true, Some(data)) = (e.dirty, &e.data) {
+                        contents.insert(*key, (data.clone(), e.codec));
+                    }
+                }
+
+                for (key, start) in Self::append(file, flushed, offsets, attributes, directories, &contents, cipher.as_ref())? {
+                    let e = entries.get_mut(&key).ok_or(PackError::InvalidEntry)?;
+                    e.start = start;
+                    e.end = start + flushed.entries[&key].length;
+                    e.dirty = false;
+                }
+
+                Ok(())
+            }
+            BackPack::Parsed {
+                file,
+                offsets,
+                attributes,
+                directories,
+                data,
+                codecs,
+                modified,
+                flushed,
+                flush_mode,
+                cipher,
+                ..
+            } => {
+                let file = file.as_mut().ok_or(Closed)?;
+                let offsets = offsets.get_mut();
+                let attributes = attributes.get_mut();
+                let directories = directories.get_mut();
+                let data = data.get_mut();
+                let codecs = codecs.get_mut();
+                let cipher = cipher.as_ref();
+                let modified = modified.get_mut();
+
+                // contents of removed files are kept while handles to them may be open
+                let referenced = offsets.values().collect::<HashSet<_>>();
+                data.retain(|key, _| referenced.contains(key));
+                codecs.retain(|key, _| referenced.contains(key));
+
+                let mut keys = offsets.values().copied().collect::<Vec<_>>();
+                keys.sort_unstable();
+                keys.dedup();
+
+                let append = *flush_mode == FlushMode::Append && flushed.can_append();
+
+                let mut contents = HashMap::new();
+                for key in keys {
+                    if append && flushed.entries.contains_key(&key) && !modified.contains(&key) {
+                        continue;
+                    }
+
+                    let blob = data.get(&key).cloned().ok_or(PackError::InvalidEntry)?;
+                    contents.insert(key, (blob, codecs.get(&key).copied().unwrap_or(STORE)));
+                }
+
+                if append {
+                    Self::append(file, flushed, offsets, attributes, directories, &contents, cipher)?;
+                    modified.clear();
+                    return Ok(());
+                }
+
+                let mut new_data = Vec::new();
+                let mut new_entries = HashMap::new();
+                let mut keys = contents.keys().copied().collect::<Vec<_>>();
+                keys.sort_unstable();
+                for key in keys {
+                    let (blob, codec) = &contents[&key];
+                    let blob = blob.read();
+                    let (mut toc_entry, stored) = Self::store(*codec, &blob, cipher)?;
+
+                    toc_entry.offset = new_data.len() as u64;
+                    new_entries.insert(key, toc_entry);
+                    new_data.extend_from_slice(&stored);
+                }
+
+                let toc = offsets.iter()
+                    .map(|(name, key)| (name.clone(), (new_entries[key], attributes.get(name).copied().unwrap_or_default())))
+                    .chain(Self::directory_toc(directories, attributes))
+                    .collect();
+                let toc_blocks = Self::create_toc(&toc, PACK_HEADER_SIZE, 0, cipher)?;
+
+                file.seek(SeekFrom::Start(0))?;
+                BackPack::write_headers(file, new_data.len() as u64, &toc_blocks)?;
+
+                file.write_all(&new_data)?;
+                file.set_len(PACK_HEADER_SIZE + toc_blocks.len() as u64 * TOC_SIZE as u64 + new_data.len() as u64)?;
+
+                *flushed = Headers {
+                    offsets: offsets.clone(),
+                    entries: new_entries,
+                    attributes: attributes.clone(),
+                    directories: directories.clone(),
+                    toc_blocks: Self::consecutive_toc_blocks(PACK_HEADER_SIZE, toc_blocks.len()),
+                    data_size: new_data.len() as u64,
+                    version: PACK_VERSION,
+                };
+                modified.clear();
+
+                Ok(())
+            }
+            // mapped packs can't be changed
+            BackPack::Mapped { .. } => Ok(()),
+        }
+    }
+
+    /// Sets how changes are written to the backing file by [`flush`](BackPack::flush),
+    /// and by [`close`](BackPack::close).
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    /// # use backpack::pack::FlushMode;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.set_flush_mode(FlushMode::Append);
+    ///
+    ///     bp.add_file_named("a", "a.txt")?;
+    ///     bp.flush()?;
+    ///     // only writes b.txt and a new table of contents block
+    ///     bp.add_file_named("b", "b.txt")?;
+    ///     bp.flush()?;
+    ///
+    ///     let bp = BackPack::open(bp.close()?)?;
+    ///     assert_eq!(&*bp.get_file("a.txt")?.get_bytes(), b"a");
+    ///     assert_eq!(&*bp.get_file("b.txt")?.get_bytes(), b"b");
+    ///     bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn set_flush_mode(&mut self, mode: FlushMode) {
+        match self {
+            BackPack::PartiallyParsed { flush_mode, .. } |
+            BackPack::Parsed { flush_mode, .. } => *flush_mode = mode,
+            BackPack::Mapped { .. } => {}
+        }
+    }
+
+    /// Enables or disables deduplication. When enabled, [`add_file`](BackPack::add_file)
+    /// refers files to existing contents in the pack when they are identical, instead of
+    /// storing them again. Enabling deduplication reads all files in the pack once, to index
+    /// their contents.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.set_deduplicate(true)?;
+    ///
+    ///     bp.add_file_named("MIT License", "a/LICENSE")?;
+    ///     // refers to the contents of a/LICENSE
+    ///     bp.add_file_named("MIT License", "b/LICENSE")?;
+    ///     assert_eq!(bp.memory_bytes(), 11);
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn set_deduplicate(&mut self, enabled: bool) -> error::Result<()> {
+        if let BackPack::Mapped { .. } = self {
+            return Err(ReadOnly);
+        }
+
+        let index = if enabled {
+            let mut keys = self.offsets().read().values().copied().collect::<Vec<_>>();
+            keys.sort_unstable();
+            keys.dedup();
+
+            let mut index = ContentIndex::new();
+            for key in keys {
+                let hash = crc32fast::hash(&self.retrieve_bytes(key)?);
+                index.entry(hash).or_default().push(key);
+            }
+
+            Some(Mutex::new(index))
+        } else {
+            None
+        };
+
+        match self {
+            BackPack::PartiallyParsed { content_index, .. } |
+            BackPack::Parsed { content_index, .. } => *content_index = index,
+            BackPack::Mapped { .. } => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Sets the codec used to compress files added with [`add_file`](BackPack::add_file).
+    /// Files are stored uncompressed by default.
+    ///
+    #[cfg_attr(feature = "deflate", doc = "```rust")]
+    #[cfg_attr(not(feature = "deflate"), doc = "```ignore")]
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    /// # use backpack::pack::codec::DEFLATE;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.set_default_codec(DEFLATE)?;
+    ///     bp.add_file_named("a".repeat(1000).as_str(), "test.txt")?;
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn set_default_codec(&mut self, codec: u16) -> error::Result<()> {
+        codec::get_codec(codec)?;
+
+        match self {
+            BackPack::PartiallyParsed { default_codec, .. } |
+            BackPack::Parsed { default_codec, .. } => *default_codec = codec,
+            BackPack::Mapped { .. } => return Err(ReadOnly),
+        }
+
+        Ok(())
+    }
+
+    fn consecutive_toc_blocks(first_toc_offset: u64, num_blocks: usize) -> Vec<u64> {
+        (0..num_blocks as u64)
+            .map(|i| first_toc_offset + i * TOC_SIZE as u64)
+            .collect()
+    }
+
+    /// Appends `contents` to the end of the pack, followed by toc blocks for all changed
+    /// and removed files, which are linked from the last toc block in the pack. Files
+    /// which refer to data which is already in the pack, are not written again. Returns
+    /// where in the file the contents of each key were written.
+    fn append(
+        file: &mut RawFile,
+        flushed: &mut Headers,
+        offsets: &BTreeMap<String, (u64, u64)>,
+        attributes: &HashMap<String, Attributes>,
+        directories: &BTreeSet<String>,
+        contents: &Contents,
+        cipher: Option<&Cipher>,
+    ) -> error::Result<HashMap<(u64, u64), u64>> {
+        let removed = flushed.offsets.keys().chain(&flushed.directories)
+            .filter(|name| !offsets.contains_key(*name) && !directories.contains(*name))
+            .collect::<Vec<_>>();
+        let changed = offsets.iter()
+            .filter(|(name, key)| contents.contains_key(*key) || flushed.offsets.get(*name) != Some(key))
+            .collect::<Vec<_>>();
+        let created = directories.difference(&flushed.directories).cloned().collect::<BTreeSet<_>>();
+        if changed.is_empty() && removed.is_empty() && created.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let end = PACK_HEADER_SIZE + flushed.data_size + flushed.toc_blocks.len() as u64 * TOC_SIZE as u64;
+        file.seek(SeekFrom::Start(end))?;
+
+        let mut keys = contents.keys().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        let mut locations = HashMap::new();
+        let mut written = 0;
+        for key in keys {
+            let (blob, codec) = &contents[&key];
+            let blob = blob.read();
+            let (mut toc_entry, stored) = Self::store(*codec, &blob, cipher)?;
+            file.write_all(&stored)?;
+
+            toc_entry.offset = flushed.data_size + written;
+            flushed.entries.insert(key, toc_entry);
+            locations.insert(key, end + written);
+            written += stored.len() as u64;
+        }
+
+        let mut toc = HashMap::new();
+        for (name, key) in changed {
+            let e = *flushed.entries.get(key).ok_or(PackError::InvalidEntry)?;
+            toc.insert(name.clone(), (e, attributes.get(name).copied().unwrap_or_default()));
+        }
+        toc.extend(removed.into_iter().map(|name| (name.clone(), (TocEntry::TOMBSTONE, Attributes::default()))));
+        toc.extend(Self::directory_toc(&created, attributes));
+
+        let first_toc_offset = end + written;
+        let toc_blocks = Self::create_toc(&toc, first_toc_offset, flushed.toc_blocks.len() as u64, cipher)?;
+        for i in &toc_blocks {
+            file.write_all(i)?;
+        }
+
+        // only link the new toc blocks after they're completely written
+        match flushed.toc_blocks.last() {
+            // the checksum of the last block covers its link (and encrypted blocks authenticate
+            // it), so the block is written again. Only the header of unencrypted blocks changes.
+            Some(&last_toc_offset) => {
+                let sequence = flushed.toc_blocks.len() as u64 - 1;
+                let (_, entries) = Self::read_toc_block(file, last_toc_offset, sequence, flushed.version, cipher)?;
+
+                let start = match cipher {
+                    Some(_) => ENCRYPTED_TOC_HEADER_SIZE + 2,
+                    None => TOC_BLOCK_HEADER_SIZE,
+                };
+                let mut curr = Cursor::new(vec![0; start]);
+                curr.set_position(start as u64);
+                curr.write_all(&entries)?;
+                let block = Self::finish_toc_block(curr, last_toc_offset, first_toc_offset, sequence, cipher)?;
+
+                file.seek(SeekFrom::Start(last_toc_offset))?;
+                match cipher {
+                    Some(_) => file.write_all(&block)?,
+                    None => file.write_all(&block[..TOC_BLOCK_HEADER_SIZE])?,
+                }
+            }
+            None => {
+                file.seek(SeekFrom::Start(FIRST_TOC_OFFSET_POSITION))?;
+                file.write_all(&first_toc_offset.to_le_bytes())?;
+            }
+        }
+
+        file.seek(SeekFrom::Start(DATA_SIZE_POSITION))?;
+        file.write_all(&(flushed.data_size + written).to_le_bytes())?;
+
+        flushed.offsets = offsets.clone();
+        flushed.attributes = attributes.clone();
+        flushed.directories = directories.clone();
+        flushed.data_size += written;
+        flushed.toc_blocks.extend(Self::consecutive_toc_blocks(first_toc_offset, toc_blocks.len()));
+
+        Ok(locations)
+    }
+
+    /// Rewrites a partially parsed pack in place. Entries are written in the order
+    /// they are stored in, so they only move when the table of contents grows or
+    /// when entries before them were removed. Entries that are about to be
+    /// overwritten are read into memory first.
+    fn flush_partial(
+        file: &mut RawFile,
+        offsets: &BTreeMap<String, (u64, u64)>,
+        attributes: &HashMap<String, Attributes>,
+        directories: &BTreeSet<String>,
+        flushed: &mut Headers,
+        entries: &mut HashMap<(u64, u64), PartialData>,
+        cipher: Option<&Cipher>,
+    ) -> error::Result<()> {
+        let mut keys = offsets.values().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+        keys.sort_by_key(|key| {
+            let e = &entries[key];
+            (e.dirty, e.start)
+        });
+
+        // changed entries are compressed (and encrypted) up front,
+        // since the toc (which comes first) contains their stored size
+        let mut compressed = HashMap::new();
+        let mut new_entries = HashMap::new();
+        let mut data_size = 0;
+        for key in &keys {
+            let e = &entries[key];
+            let mut toc_entry = match (&e.data, e.dirty) {
+                (Some(data), true) => {
+                    let data = data.read();
+                    let (toc_entry, contents) = Self::store(e.codec, &data, cipher)?;
+
+                    compressed.insert(*key, contents.into_owned());
+                    toc_entry
+                }
+                _ => *flushed.entries.get(key).ok_or(PackError::InvalidEntry)?,
+            };
+
+            toc_entry.offset = data_size;
+            data_size += toc_entry.length;
+            new_entries.insert(*key, toc_entry);
+        }
+
+        let toc = offsets.iter()
+            .map(|(name, key)| (name.clone(), (new_entries[key], attributes.get(name).copied().unwrap_or_default())))
+            .chain(Self::directory_toc(directories, attributes))
+            .collect();
+        let new_toc_blocks = Self::create_toc(&toc, PACK_HEADER_SIZE, 0, cipher)?;
+        let data_start = PACK_HEADER_SIZE + new_toc_blocks.len() as u64 * TOC_SIZE as u64;
+
+        let mut read_ahead = HashMap::new();
+        let mut next_read = 0;
+
+        Self::read_ahead(file, entries, &keys, &mut next_read, data_start, &mut read_ahead)?;
+        file.seek(SeekFrom::Start(0))?;
+        Self::write_headers(file, data_size, &new_toc_blocks)?;
+
+        for key in &keys {
+            let toc_entry = new_entries[key];
+            let start = data_start + toc_entry.offset;
+            Self::read_ahead(file, entries, &keys, &mut next_read, start + toc_entry.length, &mut read_ahead)?;
+
+            let e = entries.get_mut(key).ok_or(PackError::InvalidEntry)?;
+            let contents = match compressed.remove(key).or_else(|| read_ahead.remove(key)) {
+                Some(contents) => contents,
+                // not read ahead, so it isn't overwritten by this entry either
+                None => Self::read_entry(file, e)?,
+            };
+
+            file.seek(SeekFrom::Start(start))?;
+            file.write_all(&contents)?;
+
+            e.start = start;
+            e.end = start + toc_entry.length;
+            e.dirty = false;
+        }
+
+        file.set_len(data_start + data_size)?;
+
+        *flushed = Headers {
+            offsets: offsets.clone(),
+            entries: new_entries,
+            attributes: attributes.clone(),
+            directories: directories.clone(),
+            toc_blocks: Self::consecutive_toc_blocks(PACK_HEADER_SIZE, new_toc_blocks.len()),
+            data_size,
+            version: PACK_VERSION,
+        };
+
+        Ok(())
+    }
+
+    /// Read the stored contents of all unchanged entries which start before `until` in the backing file.
+    fn read_ahead(
+        file: &mut RawFile,
+        entries: &HashMap<(u64, u64), PartialData>,
+        keys: &[(u64, u64)],
+        next_read: &mut usize,
+        until: u64,
+        read_ahead: &mut HashMap<(u64, u64), Vec<u8>>,
+    ) -> error::Result<()> {
+        while let Some(key) = keys.get(*next_read) {
+            let e = &entries[key];
+            if e.dirty || e.start >= until {
+                break;
+            }
+
+            read_ahead.insert(*key, Self::read_entry(file, e)?);
+            *next_read += 1;
+        }
+
+        Ok(())
+    }
+
+    fn read_entry(file: &RawFile, e: &PartialData) -> error::Result<Vec<u8>> {
+        let mut buf = vec![0; (e.end - e.start) as usize];
+        file.read_exact_at(&mut buf, e.start)?;
+
+        Ok(buf)
+    }
+
+    /// Compress `data` with `codec`, and encrypt it in encrypted packs. Returns the toc
+    /// entry of the stored bytes, which still has to be given the offset they're written at.
+    fn store<'d>(codec: u16, data: &'d [u8], cipher: Option<&Cipher>) -> error::Result<(TocEntry, Cow<'d, [u8]>)> {
+        let (codec, compressed) = codec::compress(codec, data)?;
+        let (stored, nonce) = match cipher {
+            Some(cipher) => {
+                let (encrypted, nonce) = cipher.encrypt_chunked(&compressed)?;
+                (Cow::Owned(encrypted), Some(nonce))
+            }
+            None => (compressed, None),
+        };
+
+        let toc_entry = TocEntry {
+            offset: 0,
+            length: stored.len() as u64,
+            codec,
+            size: data.len() as u64,
+            checksum: crc32fast::hash(&stored),
+            nonce,
+        };
+
+        Ok((toc_entry, stored))
+    }
+
+    /// Check the bytes stored for `key`, and decrypt and decompress them.
+    fn unstore(flushed: &Headers, key: (u64, u64), stored: Vec<u8>, cipher: Option<&Cipher>) -> error::Result<Vec<u8>> {
+        let e = flushed.entries.get(&key).ok_or(PackError::InvalidEntry)?;
+
+        let compressed = match (cipher, e.nonce) {
+            (Some(cipher), Some(nonce)) if e.check(&stored) => cipher.decrypt_chunked(&nonce, &stored)
+                .ok_or_else(|| PackError::Tampered(flushed.names_of(key)))?,
+            (None, None) if e.check(&stored) => stored,
+            // damage to encrypted packs can't be told apart from tampering
+            (Some(_), _) => return Err(PackError::Tampered(flushed.names_of(key))),
+            (None, _) => return Err(PackError::Corrupted(flushed.names_of(key))),
+        };
+
+        codec::decompress(e.codec, compressed, e.size)
+    }
+
+    /// The cipher the pack is encrypted with, if it's encrypted
+    fn cipher(&self) -> Option<&Cipher> {
+        match self {
+            BackPack::PartiallyParsed { cipher, .. } |
+            BackPack::Parsed { cipher, .. } => cipher.as_ref(),
+            BackPack::Mapped { .. } => None,
+        }
+    }
+
+    /// Add a file to the pack, which is compressed with the pack's default
+    /// codec (see [`set_default_codec`](BackPack::set_default_codec)).
+    pub fn add_file<E: Into<PackError>>(&'f self, f: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<InMemoryFile<'f, 'backpack>> {
+        let codec = match self {
+            BackPack::PartiallyParsed { default_codec, .. } |
+            BackPack::Parsed { default_codec, .. } => *default_codec,
+            BackPack::Mapped { .. } => return Err(ReadOnly),
+        };
+
+        self.add_file_with_codec(f, codec)
+    }
+
+    /// Add a file to the pack, which is compressed with `codec` when the pack is flushed.
+    /// When compressing the file doesn't make it smaller, it's stored uncompressed instead.
+    ///
+    #[cfg_attr(feature = "deflate", doc = "```rust")]
+    #[cfg_attr(not(feature = "deflate"), doc = "```ignore")]
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    /// # use backpack::pack::codec::DEFLATE;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_with_codec(RawFile::in_memory("test.txt"), DEFLATE)?;
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn add_file_with_codec<E: Into<PackError>>(&'f self, f: impl TryInto<RawFile<'f, 'backpack>, Error=E>, codec: u16) -> error::Result<InMemoryFile<'f, 'backpack>> {
+        if let BackPack::Mapped { .. } = self {
+            return Err(ReadOnly);
+        }
+
+        let mut f = f.try_into().map_err(Into::<PackError>::into)?;
+        let name = normalize_entry(f.name().ok_or(NoName)?)?;
+        self.check_file_name(&name)?;
+        codec::get_codec(codec)?;
+
+        // files which don't have metadata are added without it
+        let file_attributes = f.metadata().map(|m| m.attributes).unwrap_or_default();
+
+        let mut f_data = Vec::new();
+        f.read_to_end(&mut f_data)?;
+
+        let hash = crc32fast::hash(&f_data);
+        let key = match self.find_duplicate(&f_data, hash)? {
+            Some(key) => key,
+            None => self.add_blob(f_data, codec, hash),
+        };
+
+        let (offsets, attributes) = match self {
+            BackPack::PartiallyParsed { offsets, attributes, .. } |
+            BackPack::Parsed { offsets, attributes, .. } |
+            BackPack::Mapped { offsets, attributes, .. } => (offsets, attributes),
+        };
+        let mut offsets = offsets.write();
+        if let Some(replaced) = offsets.insert(name.clone(), key) {
+            self.release_blob(&offsets, replaced);
+        }
+        drop(offsets);
+        attributes.write().insert(name.clone(), file_attributes);
+
+        Ok(InMemoryFile::Packed {
+            name: PathBuf::from(&name),
+            data: PackSlice::new(name, key.0, key.1, self),
+        })
+    }
+
+    /// Store new contents in the pack, returning their key
+    fn add_blob(&self, contents: Vec<u8>, codec: u16, hash: u32) -> (u64, u64) {
+        let key = match self {
+            BackPack::PartiallyParsed {
+                data,
+                total_pack_size,
+                accesses,
+                .. } => {
+
+                // empty files still take up a key
+                let prev = total_pack_size.fetch_add((contents.len() as u64).max(1), Ordering::SeqCst);
+                let key = (prev, contents.len() as u64);
+
+                data.lock().insert(key, PartialData {
+                    start: 0,
+                    end: 0,
+                    data: Some(Arc::new(RwLock::new(contents))),
+                    codec,
+                    dirty: true,
+                    last_used: accesses.fetch_add(1, Ordering::SeqCst),
+                });
+
+                key
+            }
+            BackPack::Parsed {
+                data,
+                codecs,
+                total_size,
+                .. } => {
+
+                // empty files still take up a key
+                let prev = total_size.fetch_add((contents.len() as u64).max(1), Ordering::SeqCst);
+                let key = (prev, contents.len() as u64);
+
+                codecs.write().insert(key, codec);
+                data.write().insert(key, Arc::new(RwLock::new(contents)));
+
+                key
+            }
+            BackPack::Mapped { .. } => unreachable!("mapped backpacks can't be changed"),
+        };
+
+        match self {
+            BackPack::PartiallyParsed { content_index: Some(index), .. } |
+            BackPack::Parsed { content_index: Some(index), .. } => {
+                index.lock().entry(hash).or_default().push(key);
+            }
+            _ => {}
+        }
+
+        key
+    }
+
+    pub fn add_empty_file(&'f self, name: impl AsRef<Path>) -> error::Result<InMemoryFile<'f, 'backpack>> {
+        self.add_file(InMemoryFile::new(name))
+    }
+
+    /// Add an empty file named `name`, unless a file by that name exists already, in which
+    /// case it fails with [`PackError::FileExists`]. Unlike checking whether the file exists
+    /// before adding it, only one of several threads creating the same file succeeds.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.create_new_file("lock")?;
+    ///     assert!(matches!(bp.create_new_file("lock"), Err(PackError::FileExists(_))));
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn create_new_file(&'f self, name: impl AsRef<Path>) -> error::Result<InMemoryFile<'f, 'backpack>> {
+        let path = name.as_ref();
+        let (offsets, attributes, codec) = match self {
+            BackPack::PartiallyParsed { offsets, attributes, default_codec, .. } |
+            BackPack::Parsed { offsets, attributes, default_codec, .. } => (offsets, attributes, *default_codec),
+            BackPack::Mapped { .. } => return Err(ReadOnly),
+        };
+
+        let name = normalize_entry(path)?;
+        self.check_file_name(&name)?;
+
+        // the file is only added after the check if no other file was added in between
+        let mut offsets = offsets.write();
+        if offsets.contains_key(&name) {
+            return Err(PackError::FileExists(path.to_path_buf()));
+        }
+        let key = self.add_blob(Vec::new(), codec, crc32fast::hash(&[]));
+        offsets.insert(name.clone(), key);
+        drop(offsets);
+        attributes.write().insert(name.clone(), Attributes::default());
+
+        Ok(InMemoryFile::Packed {
+            name: PathBuf::from(&name),
+            data: PackSlice::new(name, key.0, key.1, self),
+        })
+    }
 
-            let mut offset_bytes = [0u8; 8];
-            offset_bytes.copy_from_slice(&block[curr..curr+8]);
-            curr += 8;
-            let offset = u64::from_le_bytes(offset_bytes);
+    pub fn add_file_named<E: Into<PackError>>(&'f self, f: impl TryInto<RawFile<'f, 'backpack>, Error=E>, name: impl AsRef<Path>) -> error::Result<InMemoryFile<'f, 'backpack>> {
+        self.add_file(f.try_into().map_err(Into::<PackError>::into)?.with_name(name))
+    }
 
-            let mut length_bytes = [0u8; 8];
-            length_bytes.copy_from_slice(&block[curr..curr+8]);
-            curr += 8;
-            let length = u64::from_le_bytes(length_bytes);
+    pub fn remove_file(&self, name: impl AsRef<Path>) -> error::Result<()> {
+        let path = name.as_ref();
+        let name = normalize_entry(path)?;
+        if let BackPack::Mapped { .. } = self {
+            return Err(ReadOnly);
+        }
 
-            let string = String::from_utf8(string)?;
-            offsets.insert(string, (offset, length));
+        if self.remove_entry(&name) {
+            Ok(())
+        } else if self.is_dir(&name) {
+            Err(PackError::IsADirectory(path.to_path_buf()))
+        } else {
+            Err(PackError::FileNotFound(path.to_path_buf()))
         }
+    }
 
-        Ok(())
+    /// Remove the file `name`, returning whether it existed. Partially parsed packs keep
+    /// the contents of removed files until the next flush, since handles to them may
+    /// still be open. Parsed packs drop them right away, see [`release_blob`](Self::release_blob).
+    fn remove_entry(&self, name: &str) -> bool {
+        let attributes = match self {
+            BackPack::PartiallyParsed { attributes, .. } |
+            BackPack::Parsed { attributes, .. } => attributes,
+            BackPack::Mapped { .. } => unreachable!("mapped backpacks can't be changed"),
+        };
+
+        let mut offsets = self.offsets().write();
+        let Some(key) = offsets.remove(name) else {
+            return false;
+        };
+        self.release_blob(&offsets, key);
+        drop(offsets);
+
+        attributes.write().remove(name);
+        true
     }
 
-    fn parse_backwards_compatible(_file: &mut RawFile, version: u16) -> error::Result<(HashMap<String, (u64, u64)>, Vec<u64>)>{
-        match version {
-            _ => Err(PackError::Incompatible(version))
+    /// Drop the contents of `key` in parsed packs when no file in `offsets`, which must be
+    /// locked by the caller, refers to them anymore. Handles which are still open keep
+    /// the contents alive until they're dropped.
+    fn release_blob(&self, offsets: &BTreeMap<String, (u64, u64)>, key: (u64, u64)) {
+        if let BackPack::Parsed { data, codecs, .. } = self {
+            if !offsets.values().any(|k| *k == key) {
+                data.write().remove(&key);
+                codecs.write().remove(&key);
+            }
         }
     }
 
-    fn parse_headers(file: &mut RawFile) -> error::Result<(HashMap<String, (u64, u64)>, Vec<u64>)> {
-        let mut magic_bytes = [0u8; PACK_MAGIC.len()];
-        file.read_exact(&mut magic_bytes)?;
-        if magic_bytes != PACK_MAGIC {
-            return Err(PackError::BadMagic);
+    /// The contents of `key` in parsed packs, which handles hold on to so they can still
+    /// be used after their file was removed
+    pub(crate) fn pin(&self, key: (u64, u64)) -> Option<Blob> {
+        match self {
+            BackPack::Parsed { data, .. } => data.read().get(&key).cloned(),
+            _ => None,
         }
+    }
 
-        let mut version_bytes = [0u8; 2];
-        file.read_exact(&mut version_bytes)?;
-        let version = u16::from_le_bytes(version_bytes);
-        if version != PACK_VERSION {
-            return Self::parse_backwards_compatible(file, version);
+    /// Whether `name` is a directory: either one which was created explicitly,
+    /// or one which contains files. The root of the pack is always a directory.
+    fn is_dir(&self, name: &str) -> bool {
+        self.is_dir_in(&self.offsets().read(), name)
+    }
+
+    /// Same as [`is_dir`](Self::is_dir), for callers which hold a lock on `offsets` already.
+    fn is_dir_in(&self, offsets: &BTreeMap<String, (u64, u64)>, name: &str) -> bool {
+        if name.is_empty() {
+            return true;
         }
 
+        let prefix = format!("{}/", name);
+        let starts_with_prefix = |n: &String| n.starts_with(&prefix);
+        let directories = self.directories().read();
 
-        let mut size_bytes = [0u8; 8];
-        file.read_exact(&mut size_bytes)?;
-        let _pack_size = u64::from_le_bytes(size_bytes);
+        directories.contains(name)
+            || directories.range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded)).next().is_some_and(starts_with_prefix)
+            || offsets.range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded)).next().is_some_and(|(n, _)| starts_with_prefix(n))
+    }
 
-        let mut first_toc_offset_bytes = [0u8; 8];
-        file.read_exact(&mut first_toc_offset_bytes)?;
-        let first_toc_offset = u64::from_le_bytes(first_toc_offset_bytes);
+    /// Check that a file can be stored as `name`: it can't replace a directory,
+    /// and the directories containing it can't be files.
+    fn check_file_name(&self, name: &str) -> error::Result<()> {
+        self.check_file_name_in(&self.offsets().read(), name)
+    }
 
-        assert_eq!(file.current_offset()?, PACK_HEADER_SIZE);
+    /// Same as [`check_file_name`](Self::check_file_name), for callers which hold a lock on `offsets` already.
+    fn check_file_name_in(&self, offsets: &BTreeMap<String, (u64, u64)>, name: &str) -> error::Result<()> {
+        if self.is_dir_in(offsets, name) {
+            return Err(PackError::IsADirectory(name.into()));
+        }
 
-        let mut offsets = HashMap::new();
-        let mut toc_blocks = Vec::new();
+        match ancestors(name).find(|dir| offsets.contains_key(*dir)) {
+            Some(file) => Err(PackError::NotADirectory(file.into())),
+            None => Ok(()),
+        }
+    }
 
-        let mut next_toc_offset = first_toc_offset;
+    /// Create a new, empty directory. Its parent directory has to exist already.
+    /// Directories which contain files exist without being created, like in zip
+    /// files, but disappear again when their last file is removed.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.create_dir("assets")?;
+    ///     assert!(bp.metadata("assets")?.is_dir());
+    ///     assert!(bp.read_dir("assets")?.is_empty());
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn create_dir(&self, path: impl AsRef<Path>) -> error::Result<()> {
+        let path = path.as_ref();
+        let name = normalize_entry(path)?;
+        if let BackPack::Mapped { .. } = self {
+            return Err(ReadOnly);
+        }
 
-        while next_toc_offset != 0 {
-            toc_blocks.push(next_toc_offset);
+        if self.offsets().read().contains_key(&name) || self.is_dir(&name) {
+            return Err(PackError::FileExists(path.to_path_buf()));
+        }
 
-            file.seek(SeekFrom::Start(next_toc_offset))?;
+        let parent = name.rsplit_once('/').map_or("", |(parent, _)| parent);
+        if self.offsets().read().contains_key(parent) {
+            return Err(PackError::NotADirectory(parent.into()));
+        }
+        if !self.is_dir(parent) {
+            return Err(PackError::FileNotFound(parent.into()));
+        }
 
-            let mut toc_filled_bytes = [0u8; 2];
-            file.read_exact(&mut toc_filled_bytes)?;
-            let toc_filled = u16::from_le_bytes(toc_filled_bytes);
+        self.directories().write().insert(name);
+        Ok(())
+    }
 
-            let mut next_toc_bytes = [0u8; 8];
-            file.read_exact(&mut next_toc_bytes)?;
-            next_toc_offset = u64::from_le_bytes(next_toc_bytes);
+    /// Create a directory and all of its parents which don't exist yet.
+    pub fn create_dir_all(&self, path: impl AsRef<Path>) -> error::Result<()> {
+        let name = normalize(path.as_ref())?;
+        if let BackPack::Mapped { .. } = self {
+            return Err(ReadOnly);
+        }
+        if name.is_empty() {
+            return Ok(());
+        }
 
-            let mut toc_block_bytes = [0u8; TOC_SIZE as usize - 10];
-            file.read_exact(&mut toc_block_bytes)?;
-            Self::parse_toc_block(toc_filled - 10, &toc_block_bytes, &mut offsets)?;
+        for dir in ancestors(&name).chain(std::iter::once(name.as_str())) {
+            if self.offsets().read().contains_key(dir) {
+                return Err(PackError::NotADirectory(dir.into()));
+            }
+            if !self.is_dir(dir) {
+                self.directories().write().insert(dir.to_string());
+            }
         }
 
-        Ok((offsets, toc_blocks))
+        Ok(())
     }
 
-    pub fn open_complete<E: Into<PackError>>(file: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<Self> {
-        let mut file = file.try_into().map_err(Into::into)?;
+    /// Remove an empty directory
+    pub fn remove_dir(&self, path: impl AsRef<Path>) -> error::Result<()> {
+        let path = path.as_ref();
+        let name = self.existing_dir(path)?;
 
-        let (offsets, mut toc_blocks) = Self::parse_headers(&mut file)?;
-        toc_blocks.sort();
+        let prefix = format!("{}/", name);
+        let not_empty = self.directories().read().iter().any(|d| d.starts_with(&prefix))
+            || self.offsets().read().keys().any(|n| n.starts_with(&prefix));
+        if not_empty {
+            return Err(PackError::DirectoryNotEmpty(path.to_path_buf()));
+        }
 
-        let data = FrozenMap::new();
-        let mut total_size = 0;
+        self.directories().write().remove(&name);
+        self.remove_attributes(|n| n == name);
+        Ok(())
+    }
 
-        for (_name, (offset, length)) in &offsets {
-            let new_offset = Self::convert_offset(&toc_blocks, *offset);
-            file.seek(SeekFrom::Start(new_offset))?;
+    /// Remove a directory, and all files and directories in it
+    pub fn remove_dir_all(&self, path: impl AsRef<Path>) -> error::Result<()> {
+        let name = self.existing_dir(path.as_ref())?;
 
-            let mut buf = vec![0; *length as usize];
-            file.read_exact(&mut buf)?;
+        let prefix = format!("{}/", name);
+        let contained = |n: &str| n == name || n.starts_with(&prefix);
 
-            total_size += buf.len() as u64;
-            data.insert((*offset, *length), Box::new(RwLock::new(buf)));
+        let files = self.offsets().read().keys()
+            .filter(|n| contained(n))
+            .cloned()
+            .collect::<Vec<_>>();
+        for file in files {
+            self.remove_entry(&file);
         }
 
+        self.directories().write().retain(|d| !contained(d));
+        self.remove_attributes(contained);
+        Ok(())
+    }
 
-        Ok(Self::Parsed {
-            file: Some(file),
-            offsets: RwLock::new(offsets),
-            removals: FrozenMap::new(),
-            data,
+    /// Normalised name of the directory `path`, for removing it
+    fn existing_dir(&self, path: &Path) -> error::Result<String> {
+        let name = normalize_entry(path)?;
+        if let BackPack::Mapped { .. } = self {
+            return Err(ReadOnly);
+        }
 
-            // not closed
-            total_size: AtomicU64::new(total_size),
-            closed: false
-        })
+        if self.offsets().read().contains_key(&name) {
+            Err(PackError::NotADirectory(path.to_path_buf()))
+        } else if !self.is_dir(&name) {
+            Err(PackError::FileNotFound(path.to_path_buf()))
+        } else {
+            Ok(name)
+        }
     }
 
-    #[doc(hidden)]
-    pub fn open_partial<E: Into<PackError>>(_backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<Self> {
-        todo!()
+    /// Remove the attributes of directories which were removed
+    fn remove_attributes(&self, removed: impl Fn(&str) -> bool) {
+        let offsets = self.offsets().read();
+        match self {
+            BackPack::PartiallyParsed { attributes, .. } |
+            BackPack::Parsed { attributes, .. } |
+            BackPack::Mapped { attributes, .. } => {
+                attributes.write().retain(|n, _| offsets.contains_key(n) || !removed(n));
+            }
+        }
     }
 
-    /// Create a new pack in a file. Usually called after File::create().
-    /// Existing contents of the file are deleted.
+    /// Rename the file `from` to `to`, without copying its contents.
+    /// Fails when `from` doesn't exist or `to` already exists.
     ///
     /// ```rust
     /// # use backpack::RawFile;
@@ -277,177 +2359,415 @@ impl<'f, 'backpack: 'f> BackPack<'f, 'backpack> {
     /// # use backpack::PackError;
     ///
     /// # fn main() -> Result<(), PackError> {
-    ///     let file = RawFile::in_memory("test.bp");
-    ///     let bp = BackPack::create(file)?;
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_named("hello", "old.txt")?;
     ///
-    ///     // when done with it
-    ///     bp.close();
+    ///     bp.rename("old.txt", "new.txt")?;
+    ///     assert!(bp.get_file("old.txt").is_err());
+    ///     assert_eq!(&*bp.get_file("new.txt")?.get_bytes(), b"hello");
+    /// #   bp.close()?;
     /// #   Ok(())
     /// # }
     /// ```
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> error::Result<()> {
+        self.link(from.as_ref(), to.as_ref(), true)
+    }
+
+    /// Copy the file `from` to `to`. Both files share their contents until
+    /// either of them is written to. Fails when `from` doesn't exist or `to`
+    /// already exists.
     ///
-    pub fn create<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<Self> {
-        let mut file = backing.try_into().map_err(Into::into)?;
-        file.seek(SeekFrom::Start(0))?;
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_named("hello", "a.txt")?;
+    ///
+    ///     bp.copy("a.txt", "b.txt")?;
+    ///     assert_eq!(&*bp.get_file("b.txt")?.get_bytes(), b"hello");
+    ///     // the contents are only stored once
+    ///     assert_eq!(bp.memory_bytes(), 5);
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn copy(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> error::Result<()> {
+        self.link(from.as_ref(), to.as_ref(), false)
+    }
 
-        Ok(Self::Parsed {
-            file: Some(file),
-            offsets: Default::default(),
-            removals: FrozenMap::new(),
-            data: FrozenMap::new(),
-            // not closed
-            total_size: AtomicU64::new(0),
-            closed: false,
+    /// Make `to` refer to the contents of `from`, with the same attributes.
+    fn link(&self, from: &Path, to: &Path, remove_from: bool) -> error::Result<()> {
+        let attributes = match self {
+            BackPack::PartiallyParsed { attributes, .. } |
+            BackPack::Parsed { attributes, .. } => attributes,
+            BackPack::Mapped { .. } => return Err(ReadOnly),
+        };
+        let from_name = normalize_entry(from)?;
+        let to_name = normalize_entry(to)?;
+
+        // checked under the same lock as the file is added with, so no file is added in between
+        let mut offsets = self.offsets().write();
+        if offsets.contains_key(&to_name) || self.is_dir_in(&offsets, &to_name) {
+            return Err(PackError::FileExists(to.to_path_buf()));
+        }
+        self.check_file_name_in(&offsets, &to_name)?;
+        let key = match offsets.get(&from_name) {
+            Some(key) => *key,
+            None if self.is_dir_in(&offsets, &from_name) => return Err(PackError::IsADirectory(from.to_path_buf())),
+            None => return Err(PackError::FileNotFound(from.to_path_buf())),
+        };
+
+        let mut attributes = attributes.write();
+        let file_attributes = if remove_from {
+            offsets.remove(&from_name);
+            attributes.remove(&from_name)
+        } else {
+            attributes.get(&from_name).copied()
+        };
+
+        offsets.insert(to_name.clone(), key);
+        attributes.insert(to_name, file_attributes.unwrap_or_default());
+
+        Ok(())
+    }
+
+    pub fn get_file(&'f self, name: impl AsRef<Path>) -> error::Result<InMemoryFile<'f, 'backpack>> {
+        let path = name.as_ref();
+        let name = normalize_entry(path)?;
+
+        let key = self.offsets().read().get(&name).copied();
+        let (offset, length) = match key {
+            Some(key) => key,
+            None if self.is_dir(&name) => return Err(PackError::IsADirectory(path.to_path_buf())),
+            None => return Err(PackError::FileNotFound(path.to_path_buf())),
+        };
+
+        Ok(InMemoryFile::Packed {
+            name: PathBuf::from(&name),
+            data: PackSlice::new(name, offset, length, self)
         })
     }
 
-    /// Gets the number of bytes used to store files currently.
-    /// If packs get really large (contain lots of files) you
-    /// might want to flush
-    pub fn memory_bytes(&self) -> usize {
-        match self {
-            BackPack::PartiallyParsed { .. } => todo!(),
-            BackPack::Parsed { total_size, .. } => {
-                total_size.load(Ordering::SeqCst) as usize
-            },
+    /// Get a read-only handle to a file, which reads its contents from the backing file
+    /// when they're needed instead of loading them into memory, see [`LazyFile`].
+    pub fn get_file_lazy(&'f self, name: impl AsRef<Path>) -> error::Result<LazyFile<'f, 'backpack>> {
+        match self.get_file(name)? {
+            InMemoryFile::Packed { data, .. } => Ok(LazyFile::new(data)),
+            _ => unreachable!("files in a backpack are always packed"),
         }
     }
 
-    /// Write all changes since the last flush to the file
+    /// Open a backpack which is stored as a file in this backpack. Changes to the nested pack
+    /// are written to the file when it's flushed, after which they're saved like changes to
+    /// any other file when this pack is flushed. Nested packs which are encrypted or have to
+    /// be opened partially can be opened from [`get_file`](BackPack::get_file) instead.
     ///
     /// ```rust
     /// # use backpack::RawFile;
-    /// # use backpack::pack::BackPack;
-    /// # use backpack::pack::PackError;
-    /// # use std::io::Read;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
     ///
     /// # fn main() -> Result<(), PackError> {
-    ///     let file = RawFile::in_memory("test.bp");
-    ///     let bp = BackPack::create(file)?;
-    ///
-    ///     // when done with it
-    ///     let mut file = bp.close()?;
-    ///     // now there's stuff in the file, which can be read again with `open`
-    ///     let bp = BackPack::open(file)?;
+    ///     let bp = BackPack::create(RawFile::in_memory("locales.bp"))?;
+    ///     let nested = bp.create_nested("en.bp")?;
+    ///     nested.add_file_named("hello", "greeting.txt")?;
+    ///     nested.close()?;
     ///
+    ///     let nested = bp.open_nested("en.bp")?;
+    ///     assert_eq!(&*nested.get_file("greeting.txt")?.get_bytes(), b"hello");
+    /// #   nested.close()?;
+    /// #   bp.close()?;
     /// #   Ok(())
     /// # }
-    ///
     /// ```
-    pub fn flush(&mut self) -> error::Result<()> {
-        match self {
-            BackPack::PartiallyParsed { .. } => { todo!() }
-            BackPack::Parsed {
-                file,
-                offsets,
-                data,
-                removals,
-                ..
-            } => {
-                let mut new_data = Vec::new();
-                let mut new_offsets = HashMap::new();
-                for (name, (start, length)) in offsets.read().deref() {
-                    let contents = data.get(&(*start, *length))
-                        .ok_or(PackError::InvalidEntry)?;
+    pub fn open_nested(&'f self, name: impl AsRef<Path>) -> error::Result<Self> {
+        Self::open(self.get_file(name)?)
+    }
 
-                    if removals.get(name).is_some() {
-                        continue
-                    }
+    /// Create a new backpack which is stored as a file named `name` in this
+    /// backpack, see [`open_nested`](BackPack::open_nested).
+    pub fn create_nested(&'f self, name: impl AsRef<Path>) -> error::Result<Self> {
+        Self::create(self.add_empty_file(name)?)
+    }
 
-                    new_offsets.insert(name.clone(), (new_data.len() as u64, *length));
-                    new_data.extend(contents.read().deref());
-                }
+    fn directories(&self) -> &RwLock<BTreeSet<String>> {
+        match self {
+            BackPack::PartiallyParsed { directories, .. } |
+            BackPack::Parsed { directories, .. } |
+            BackPack::Mapped { directories, .. } => directories,
+        }
+    }
 
-                if let Some(file) = file {
-                    file.seek(SeekFrom::Start(0))?;
-                    BackPack::write_headers(file, new_data.len() as u64, &new_offsets)?;
+    fn offsets(&self) -> &RwLock<BTreeMap<String, (u64, u64)>> {
+        match self {
+            BackPack::PartiallyParsed { offsets, .. } |
+            BackPack::Parsed { offsets, .. } |
+            BackPack::Mapped { offsets, .. } => offsets,
+        }
+    }
 
-                    file.write_all(&new_data)?;
-                    Ok(())
-                } else {
-                    Err(Closed)
+    /// Current size of the contents of `key`
+    fn size_of(&self, key: (u64, u64)) -> u64 {
+        match self {
+            BackPack::PartiallyParsed { data, flushed, .. } => {
+                match data.lock().get(&key).and_then(|e| e.data.as_ref()) {
+                    Some(data) => data.read().len() as u64,
+                    None => flushed.entries.get(&key).map_or(0, |e| e.size),
                 }
             }
+            BackPack::Parsed { data, .. } => {
+                data.read().get(&key).map_or(0, |d| d.read().len() as u64)
+            }
+            BackPack::Mapped { flushed, .. } => flushed.entries.get(&key).map_or(0, |e| e.size),
         }
     }
 
-    pub fn add_file<E: Into<PackError>>(&'f self, f: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<InMemoryFile<'f, 'backpack>> {
-        let mut f = f.try_into().map_err(Into::<PackError>::into)?;
-
-        match self {
-            BackPack::PartiallyParsed { .. } => todo!(),
-            BackPack::Parsed {
-                offsets,
-                data,
-                total_size,
-                .. } => {
-
-                let mut f_data = Vec::new();
-                f.read_to_end(&mut f_data)?;
-                let prev = total_size.fetch_add(f_data.len() as u64, Ordering::SeqCst);
-                let key = (prev, f_data.len() as u64);
-
-                let name = f.name().ok_or(NoName)?;
+    /// Metadata of the file `name` which refers to `key`
+    pub(crate) fn metadata_of(&self, name: &str, key: (u64, u64)) -> EntryMetadata {
+        let attributes = match self {
+            BackPack::PartiallyParsed { offsets, attributes, .. } |
+            BackPack::Parsed { offsets, attributes, .. } |
+            BackPack::Mapped { offsets, attributes, .. } => {
+                // handles to files which were replaced since don't share their metadata
+                match offsets.read().get(name) {
+                    Some(k) if *k == key => attributes.read().get(name).copied().unwrap_or_default(),
+                    _ => Attributes::default(),
+                }
+            }
+        };
 
-                offsets.write().deref_mut().insert(name.to_string_lossy().into_owned(), key);
-                data.insert(key, Box::new(RwLock::new(f_data)));
+        EntryMetadata {
+            len: self.size_of(key),
+            attributes,
+            is_dir: false,
+        }
+    }
 
-                Ok(InMemoryFile::Packed {
-                    name: name.to_path_buf(),
-                    data: PackSlice::new(key.0, key.1, self),
+    /// Get the metadata of a file in the pack. The modification time and
+    /// permissions of files added from disk are kept when they are packed.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_named("hello", "test.txt")?;
+    ///
+    ///     let metadata = bp.metadata("test.txt")?;
+    ///     assert_eq!(metadata.len(), 5);
+    ///     // files from memory don't have a modification time
+    ///     assert!(metadata.modified().is_err());
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn metadata(&self, name: impl AsRef<Path>) -> error::Result<EntryMetadata> {
+        let path = name.as_ref();
+        let name = normalize(path)?;
+
+        let key = self.offsets().read().get(&name).copied();
+        match key {
+            Some(key) => Ok(self.metadata_of(&name, key)),
+            None if self.is_dir(&name) => {
+                let attributes = match self {
+                    BackPack::PartiallyParsed { attributes, .. } |
+                    BackPack::Parsed { attributes, .. } |
+                    BackPack::Mapped { attributes, .. } => attributes.read().get(&name).copied(),
+                };
+
+                Ok(EntryMetadata {
+                    len: 0,
+                    attributes: attributes.unwrap_or_default(),
+                    is_dir: true,
                 })
             }
+            None => Err(PackError::FileNotFound(path.to_path_buf())),
         }
     }
 
-    pub fn add_empty_file(&'f self, name: impl AsRef<Path>) -> error::Result<InMemoryFile<'f, 'backpack>> {
-        self.add_file(InMemoryFile::new(name))
+    fn entry(&'f self, name: String, key: (u64, u64)) -> Entry<'f, 'backpack> {
+        Entry {
+            name,
+            size: self.size_of(key),
+            key,
+            pack: self,
+        }
     }
 
-    pub fn add_file_named<E: Into<PackError>>(&'f self, f: impl TryInto<RawFile<'f, 'backpack>, Error=E>, name: impl AsRef<Path>) -> error::Result<InMemoryFile<'f, 'backpack>> {
-        self.add_file(f.try_into().map_err(Into::<PackError>::into)?.with_name(name))
+    /// List all files in the pack, sorted by name.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_named("hello", "test.txt")?;
+    ///
+    ///     for entry in bp.entries() {
+    ///         assert_eq!(entry.name(), "test.txt");
+    ///         assert_eq!(entry.size(), 5);
+    ///     }
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn entries(&'f self) -> Vec<Entry<'f, 'backpack>> {
+        let files = self.offsets().read().iter()
+            .map(|(name, key)| (name.clone(), *key))
+            .collect::<Vec<_>>();
+
+        files.into_iter()
+            .map(|(name, key)| self.entry(name, key))
+            .collect()
     }
 
-    pub fn remove_file(&mut self, name: impl AsRef<Path>) -> error::Result<()> {
-        let name = name.as_ref();
-        match self {
-            BackPack::PartiallyParsed { .. } => todo!(),
-            BackPack::Parsed {
-                offsets,
-                removals,
-                ..
-            } => {
-                if let Some(ref _identifier) = offsets.write().remove(name.to_string_lossy().as_ref()) {
-                    removals.insert(name.to_string_lossy().into_owned(), &());
-                    Ok(())
-                } else {
-                    Err(PackError::FileNotFound(name.to_path_buf()))
-                }
-            }
-        }
+    /// Iterate over all files in the pack, see [`entries`](BackPack::entries).
+    pub fn iter(&'f self) -> std::vec::IntoIter<Entry<'f, 'backpack>> {
+        self.entries().into_iter()
     }
 
-    pub fn get_file(&'f self, name: impl AsRef<Path>) -> error::Result<InMemoryFile<'f, 'backpack>> {
-        match self {
-            BackPack::PartiallyParsed { .. } => todo!(),
-            BackPack::Parsed { offsets, removals, .. } => {
-                let path_buf = name.as_ref().to_path_buf();
+    /// List the files and directories directly inside the directory `prefix`,
+    /// sorted by name. Names in a backpack form a hierarchy separated by `/`.
+    /// Directories exist when they were created with [`create_dir`](BackPack::create_dir),
+    /// or as long as they contain a file. Files in subdirectories of `prefix`
+    /// are skipped without visiting each of them.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_named("hello", "assets/test.txt")?;
+    ///     bp.add_file_named("world", "assets/textures/test.png")?;
+    ///
+    ///     let names = bp.read_dir("assets")?.iter()
+    ///         .map(|e| e.name().to_string())
+    ///         .collect::<Vec<_>>();
+    ///     assert_eq!(names, vec!["assets/test.txt", "assets/textures"]);
+    /// #   bp.close()?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn read_dir(&'f self, prefix: impl AsRef<Path>) -> error::Result<Vec<DirEntry<'f, 'backpack>>> {
+        let path = prefix.as_ref();
+        let prefix = normalize(path)?;
+        let dir = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", prefix)
+        };
 
-                // path when removal is not yet updated in main
-                if removals.get(name.as_ref().to_string_lossy().as_ref()).is_some() {
-                    return Err(PackError::FileNotFound(path_buf.clone()));
+        if self.offsets().read().contains_key(&prefix) {
+            return Err(PackError::NotADirectory(path.to_path_buf()));
+        }
+        if !self.is_dir(&prefix) {
+            return Err(PackError::FileNotFound(path.to_path_buf()));
+        }
+
+        let mut found = BTreeMap::new();
+        {
+            let offsets = self.offsets().read();
+            let mut range = offsets.range::<str, _>((Bound::Included(dir.as_str()), Bound::Unbounded));
+
+            while let Some((name, key)) = range.next() {
+                let rest = match name.strip_prefix(&dir) {
+                    Some(rest) => rest,
+                    None => break,
+                };
+
+                match rest.split_once('/') {
+                    Some((subdir, _)) => {
+                        let subdir = format!("{}{}", dir, subdir);
+
+                        // '0' is the character after '/', so this skips all files in the subdirectory
+                        range = offsets.range::<str, _>((Bound::Included(format!("{}0", subdir).as_str()), Bound::Unbounded));
+                        found.insert(subdir, None);
+                    }
+                    None => {
+                        found.insert(name.clone(), Some(*key));
+                    }
                 }
+            }
 
-                let r = offsets.read();
-                let (offset, length) = r.get(name.as_ref().to_string_lossy().as_ref())
-                    .ok_or_else(|| PackError::FileNotFound(path_buf.clone()))?;
+            // there are far fewer explicit directories than files, so they aren't skipped
+            let directories = self.directories().read();
+            for name in directories.range::<str, _>((Bound::Included(dir.as_str()), Bound::Unbounded)) {
+                let rest = match name.strip_prefix(&dir) {
+                    Some(rest) => rest,
+                    None => break,
+                };
 
-                Ok(InMemoryFile::Packed {
-                    name: path_buf,
-                    data: PackSlice::new(*offset, *length, self)
-                })
+                let subdir = rest.split_once('/').map_or(rest, |(subdir, _)| subdir);
+                found.insert(format!("{}{}", dir, subdir), None);
             }
         }
+
+        Ok(found.into_iter()
+            .map(|(name, key)| match key {
+                Some(key) => DirEntry::File(self.entry(name, key)),
+                None => DirEntry::Directory(name),
+            })
+            .collect())
+    }
+
+    /// Write a copy of all files and directories in the pack into a new pack at `path`, which
+    /// is encrypted with the same key as this pack. Unlike [`close`](BackPack::close), this
+    /// pack stays open and files in it can be in use.
+    ///
+    /// ```rust
+    /// # use backpack::RawFile;
+    /// # use backpack::BackPack;
+    /// # use backpack::PackError;
+    ///
+    /// # fn main() -> Result<(), PackError> {
+    ///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+    ///     bp.add_file_named("hello", "test.txt")?;
+    ///
+    ///     let path = std::env::temp_dir().join("backpack_doctest_save_as.bp");
+    ///     bp.save_as(&path)?;
+    ///     let saved = BackPack::open_mmap(&path)?;
+    ///     assert_eq!(saved.metadata("test.txt")?.len(), 5);
+    /// #   saved.close()?;
+    /// #   bp.close()?;
+    /// #   std::fs::remove_file(path)?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub fn save_as(&self, path: impl AsRef<Path>) -> error::Result<()> {
+        let files = self.offsets().read().iter()
+            .map(|(name, key)| (name.clone(), *key))
+            .collect::<Vec<_>>();
+        let directories = self.directories().read().clone();
+        let (attributes, default_codec) = match self {
+            BackPack::PartiallyParsed { attributes, default_codec, .. } |
+            BackPack::Parsed { attributes, default_codec, .. } => (attributes.read().clone(), *default_codec),
+            BackPack::Mapped { attributes, .. } => (attributes.read().clone(), STORE),
+        };
+
+        let mut target = BackPack::create_with_cipher(RawFile::create(path)?, self.cipher().cloned())?;
+        target.set_default_codec(default_codec)?;
+        for (name, key) in files {
+            let contents = self.retrieve_bytes(key)?.to_vec();
+            target.add_file_named(contents, &name)?;
+        }
+        for dir in &directories {
+            target.create_dir_all(dir)?;
+        }
+        if let BackPack::Parsed { attributes: target_attributes, .. } = &target {
+            *target_attributes.write() = attributes;
+        }
+
+        target.close()?;
+        Ok(())
     }
 
     /// Close a backpack, saving unsaved additions.
@@ -487,7 +2807,8 @@ impl<'f, 'backpack: 'f> BackPack<'f, 'backpack> {
         // make sure closing doesn't panic
         match &mut self {
             BackPack::PartiallyParsed { closed, file, .. } |
-            BackPack::Parsed { closed, file, .. } => {
+            BackPack::Parsed { closed, file, .. } |
+            BackPack::Mapped { closed, file, .. } => {
                 *closed = true;
                 let mut file = file.take().ok_or(Closed)?;
                 file.seek(SeekFrom::Start(0))?;
@@ -496,6 +2817,27 @@ impl<'f, 'backpack: 'f> BackPack<'f, 'backpack> {
         }
     }
 
+    /// Makes dropping the pack discard unsaved changes instead of saving them,
+    /// for packs which only live in memory. [`close`](BackPack::close) still saves them.
+    pub(crate) fn discard_on_drop(&mut self) {
+        match self {
+            BackPack::PartiallyParsed { closed, .. } |
+            BackPack::Parsed { closed, .. } |
+            BackPack::Mapped { closed, .. } => *closed = true,
+        }
+    }
+
+    /// An empty, closed pack, which takes the place of a pack moved out of a reference
+    #[cfg(feature = "async")]
+    pub(crate) fn closed() -> Self {
+        let mut bp = Self::create(InMemoryFile::unnamed()).expect("failed to create backpack");
+        if let BackPack::Parsed { file, closed, .. } = &mut bp {
+            *file = None;
+            *closed = true;
+        }
+        bp
+    }
+
     fn best_effort_flush(&mut self) {
         if let Err(e) = self.flush() {
             panic!("failed to flush to disk. backpack likely corrupted. {}", e);
@@ -507,7 +2849,8 @@ impl<'f, 'backpack> Drop for BackPack<'f, 'backpack> {
     fn drop(&mut self) {
         match &self {
             BackPack::PartiallyParsed {closed,  .. } |
-            BackPack::Parsed { closed, .. } => {
+            BackPack::Parsed { closed, .. } |
+            BackPack::Mapped { closed, .. } => {
                 if !closed {
                     log::warn!("dropping unsaved backpack may panic. Attempting best-effort cleanup.");
                     self.best_effort_flush();
@@ -517,3 +2860,12 @@ impl<'f, 'backpack> Drop for BackPack<'f, 'backpack> {
         }
     }
 }
+
+impl<'f, 'backpack: 'f> IntoIterator for &'f BackPack<'f, 'backpack> {
+    type Item = Entry<'f, 'backpack>;
+    type IntoIter = std::vec::IntoIter<Entry<'f, 'backpack>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
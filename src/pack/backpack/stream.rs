@@ -0,0 +1,550 @@
+use std::collections::VecDeque;
+use std::io::{Cursor, Read};
+use crate::error;
+use crate::error::PackError;
+use crate::pack::backpack::{BackPack, Toc, TocEntry, ENTRY_MAGIC};
+use crate::pack::codec::{self, Decoder, STORE};
+use crate::pack::metadata::{Attributes, EntryMetadata};
+use crate::pack::{DIRECTORY, PACK_HEADER_SIZE, PACK_MAGIC, PACK_VERSION, TOC_SIZE};
+
+/// Number of stored bytes of compressed entries which are read at once
+const DECODE_CHUNK_SIZE: usize = 1 << 16;
+
+/// Reads the entries of a pack one after the other, in the order they're stored in,
+/// from a stream which can't seek, like a pipe, a socket or a decompressor.
+///
+/// Only packs of which the table of contents is stored before the data can be read
+/// this way, which is the case after they're flushed with [`FlushMode::Rewrite`], and
+/// packs written by [`PackWriter`], which stores a header before every entry. Packs which
+/// were appended to return [`PackError::NotStreamable`]. For packs written by a
+/// [`PackWriter`] this is only noticed after all entries were read, when the headers
+/// are compared with the table of contents.
+///
+/// [`FlushMode::Rewrite`]: crate::pack::FlushMode::Rewrite
+/// [`PackWriter`]: crate::pack::PackWriter
+///
+/// ```rust
+/// # use std::io::Read;
+/// # use backpack::RawFile;
+/// # use backpack::PackError;
+/// # use backpack::pack::{PackStreamReader, PackWriter};
+///
+/// # fn main() -> Result<(), PackError> {
+///     let mut writer = PackWriter::create(RawFile::in_memory("test.bp"))?;
+///     writer.add_file_named("hello".as_bytes(), "hello.txt")?;
+///     writer.add_file_named("world".as_bytes(), "world.txt")?;
+///     let mut file = writer.finish()?;
+///
+///     let mut pack = Vec::new();
+///     file.read_to_end(&mut pack)?;
+///
+///     let mut reader = PackStreamReader::new(pack.as_slice())?;
+///     let mut entry = reader.next_entry()?.unwrap();
+///     assert_eq!(entry.name(), "hello.txt");
+///     let mut contents = String::new();
+///     entry.read_to_string(&mut contents)?;
+///     assert_eq!(contents, "hello");
+///
+///     assert_eq!(reader.next_entry()?.unwrap().name(), "world.txt");
+///     assert!(reader.next_entry()?.is_none());
+/// #   Ok(())
+/// # }
+/// ```
+pub struct PackStreamReader<R> {
+    stream: R,
+    /// number of bytes read from the stream
+    position: u64,
+    /// format version of the pack
+    version: u16,
+    layout: Layout,
+}
+
+/// Where the reader finds out which entries are in the pack
+enum Layout {
+    /// The table of contents is stored before the data. Holds the entries which weren't
+    /// read yet, sorted by where they're stored, and where the data starts.
+    TocFirst {
+        entries: VecDeque<(String, TocEntry, Attributes)>,
+        data_start: u64,
+        /// contents of files which have the same contents as the next file
+        shared: Option<((u64, u64), Vec<u8>)>,
+    },
+    /// Every entry is preceded by a header, and the table of contents follows the data.
+    Inline {
+        /// where the next header is stored
+        next: u64,
+        data_end: u64,
+        first_toc_offset: u64,
+        /// entries which were read, which are checked against the table of contents
+        read: Toc,
+    },
+    Finished,
+}
+
+impl<R: Read> PackStreamReader<R> {
+    /// Read the headers of the pack at the start of `stream`.
+    pub fn new(stream: R) -> error::Result<Self> {
+        let mut reader = Self {
+            stream,
+            position: 0,
+            version: PACK_VERSION,
+            layout: Layout::Finished,
+        };
+
+        let mut header = [0u8; PACK_HEADER_SIZE as usize];
+        reader.fill(&mut header)?;
+        if &header[..PACK_MAGIC.len()] != PACK_MAGIC {
+            return Err(PackError::BadMagic);
+        }
+
+        let mut version_bytes = [0u8; 2];
+        version_bytes.copy_from_slice(&header[PACK_MAGIC.len()..PACK_MAGIC.len() + 2]);
+        let version = u16::from_le_bytes(version_bytes);
+        if !(1..=PACK_VERSION).contains(&version) {
+            return Err(PackError::Incompatible(version));
+        }
+        reader.version = version;
+
+        let mut size_bytes = [0u8; 8];
+        size_bytes.copy_from_slice(&header[PACK_MAGIC.len() + 2..PACK_MAGIC.len() + 10]);
+        let data_size = u64::from_le_bytes(size_bytes);
+
+        let mut first_toc_offset_bytes = [0u8; 8];
+        first_toc_offset_bytes.copy_from_slice(&header[PACK_MAGIC.len() + 10..]);
+        let first_toc_offset = u64::from_le_bytes(first_toc_offset_bytes);
+
+        reader.layout = match first_toc_offset {
+            // empty packs don't have a table of contents
+            0 => Layout::Finished,
+            PACK_HEADER_SIZE => {
+                let mut entries = reader.read_toc()?.into_iter()
+                    .map(|(name, (e, attributes))| (name, e, attributes))
+                    .collect::<Vec<_>>();
+                // directories don't have contents, so they come first. Empty files
+                // have the same offset as the contents stored after them.
+                entries.sort_by(|(a, a_entry, _), (b, b_entry, _)| {
+                    (a_entry.offset != DIRECTORY, a_entry.offset, a_entry.length, a)
+                        .cmp(&(b_entry.offset != DIRECTORY, b_entry.offset, b_entry.length, b))
+                });
+
+                Layout::TocFirst {
+                    entries: entries.into(),
+                    data_start: reader.position,
+                    shared: None,
+                }
+            }
+            _ => Layout::Inline {
+                next: PACK_HEADER_SIZE,
+                data_end: PACK_HEADER_SIZE.saturating_add(data_size),
+                first_toc_offset,
+                read: Toc::new(),
+            },
+        };
+
+        Ok(reader)
+    }
+
+    /// The next entry in the pack, or `None` after the last one. Contents of the
+    /// previous entry which weren't read are skipped.
+    pub fn next_entry(&mut self) -> error::Result<Option<StreamEntry<'_, R>>> {
+        match self.layout {
+            Layout::TocFirst { .. } => self.next_listed(),
+            Layout::Inline { .. } => self.next_inline(),
+            Layout::Finished => Ok(None),
+        }
+    }
+
+    /// Returns the stream, positioned after the last byte which was read from it.
+    pub fn into_inner(self) -> R {
+        self.stream
+    }
+
+    fn next_listed(&mut self) -> error::Result<Option<StreamEntry<'_, R>>> {
+        let Layout::TocFirst { entries, data_start, shared } = &mut self.layout else {
+            unreachable!("entries are only listed when the toc is stored first");
+        };
+        let Some((name, e, attributes)) = entries.pop_front() else {
+            self.layout = Layout::Finished;
+            return Ok(None);
+        };
+        if e.offset == DIRECTORY {
+            return Ok(Some(StreamEntry::buffered(name, e, attributes, Vec::new())));
+        }
+
+        // files with the same contents refer to the same bytes, which can only be read once
+        let shared_with_next = entries.front().is_some_and(|(_, next, _)| next.key() == e.key());
+        let start = data_start.checked_add(e.offset).ok_or(PackError::CorruptedToc(PACK_HEADER_SIZE))?;
+        let contents = match shared.take() {
+            Some((key, contents)) if key == e.key() => Some(contents),
+            _ => None,
+        };
+
+        let contents = match contents {
+            Some(contents) => contents,
+            None if shared_with_next => {
+                self.skip_to(start)?;
+                self.read_contents(&name, &e)?
+            }
+            None => {
+                self.skip_to(start)?;
+                return self.entry(name, e, attributes).map(Some);
+            }
+        };
+
+        if shared_with_next {
+            if let Layout::TocFirst { shared, .. } = &mut self.layout {
+                *shared = Some((e.key(), contents.clone()));
+            }
+        }
+        Ok(Some(StreamEntry::buffered(name, e, attributes, contents)))
+    }
+
+    fn next_inline(&mut self) -> error::Result<Option<StreamEntry<'_, R>>> {
+        let Layout::Inline { next, data_end, first_toc_offset, .. } = self.layout else {
+            unreachable!("entries are only read inline when they have headers");
+        };
+        self.skip_to(next)?;
+
+        if self.position == data_end {
+            // appended toc blocks aren't stored after the data the headers describe
+            if first_toc_offset != data_end {
+                return Err(PackError::NotStreamable);
+            }
+            let toc = self.read_toc()?;
+            if let Layout::Inline { read, .. } = &self.layout {
+                if toc != *read {
+                    return Err(PackError::NotStreamable);
+                }
+            }
+
+            self.layout = Layout::Finished;
+            return Ok(None);
+        }
+
+        let start = self.position;
+        let mut magic = [0u8; ENTRY_MAGIC.len()];
+        self.fill(&mut magic)?;
+        if magic != ENTRY_MAGIC {
+            return Err(PackError::NotStreamable);
+        }
+
+        let mut record = vec![0u8; 2];
+        self.fill(&mut record)?;
+        let strlen = u16::from_le_bytes([record[0], record[1]]) as usize;
+        record.resize(TocEntry::SIZE + strlen, 0);
+        self.fill(&mut record[2..])?;
+
+        let mut checksum_bytes = [0u8; 4];
+        self.fill(&mut checksum_bytes)?;
+        if u32::from_le_bytes(checksum_bytes) != crc32fast::hash(&record) {
+            return Err(PackError::CorruptedToc(start));
+        }
+
+        let (name, e, attributes) = BackPack::parse_toc_entry(&record, start, &mut 0, false)?;
+        let end = match e.offset {
+            DIRECTORY => Some(self.position),
+            offset if offset.checked_add(PACK_HEADER_SIZE) == Some(self.position) => self.position.checked_add(e.length),
+            _ => None,
+        };
+        let end = match end {
+            Some(end) if end <= data_end => end,
+            _ => return Err(PackError::CorruptedToc(start)),
+        };
+
+        if let Layout::Inline { next, read, .. } = &mut self.layout {
+            *next = end;
+            read.insert(name.clone(), (e, attributes));
+        }
+
+        match e.offset {
+            DIRECTORY => Ok(Some(StreamEntry::buffered(name, e, attributes, Vec::new()))),
+            _ => self.entry(name, e, attributes).map(Some),
+        }
+    }
+
+    /// The entry of which the stored bytes are next in the stream. Its contents are read
+    /// straight from the stream, compressed entries are decompressed while they're read.
+    fn entry(&mut self, name: String, e: TocEntry, attributes: Attributes) -> error::Result<StreamEntry<'_, R>> {
+        let decoder = match e.codec {
+            STORE => None,
+            codec => match codec::decoder(codec, e.size)? {
+                Some(decoder) => Some(decoder),
+                // codecs which can't decompress piece by piece get all stored bytes at once
+                None => {
+                    let contents = self.read_contents(&name, &e)?;
+                    return Ok(StreamEntry::buffered(name, e, attributes, contents));
+                }
+            },
+        };
+
+        let stored = Stored {
+            name: name.clone(),
+            reader: self,
+            remaining: e.length,
+            hasher: crc32fast::Hasher::new(),
+            checksum: e.checksum,
+        };
+        let contents = match decoder {
+            Some(decoder) => Contents::Decoded {
+                stored,
+                decoder,
+                input: Vec::new(),
+                start: 0,
+                remaining: e.size,
+            },
+            None => Contents::Stored(stored),
+        };
+
+        Ok(StreamEntry {
+            name,
+            metadata: EntryMetadata { len: e.size, attributes, is_dir: false },
+            contents,
+        })
+    }
+
+    /// Read, check and decompress the stored bytes of `e`, which are next in the stream.
+    fn read_contents(&mut self, name: &str, e: &TocEntry) -> error::Result<Vec<u8>> {
+        let mut stored = vec![0; e.length as usize];
+        self.fill(&mut stored)?;
+        if !e.check(&stored) {
+            return Err(PackError::Corrupted(vec![name.to_string()]));
+        }
+
+        codec::decompress(e.codec, stored, e.size)
+    }
+
+    /// Read consecutive toc blocks, starting at the current position.
+    fn read_toc(&mut self) -> error::Result<Toc> {
+        let mut toc = Toc::new();
+        let mut sequence = 0;
+        loop {
+            let offset = self.position;
+            let mut block = vec![0; TOC_SIZE as usize];
+            self.fill(&mut block)?;
+
+            let (next, entries) = BackPack::decode_toc_block(&block, offset, sequence, self.version, None)?;
+            BackPack::parse_toc_block(&entries, offset, false, &mut toc)?;
+
+            match next {
+                0 => return Ok(toc),
+                next if next == self.position => sequence += 1,
+                // toc blocks which were appended are stored after data which was appended
+                _ => return Err(PackError::NotStreamable),
+            }
+        }
+    }
+
+    /// Skip the bytes up to `position`, which mustn't have been read yet.
+    fn skip_to(&mut self, position: u64) -> error::Result<()> {
+        if position < self.position {
+            return Err(PackError::NotStreamable);
+        }
+
+        let skipped = std::io::copy(&mut (&mut self.stream).take(position - self.position), &mut std::io::sink())?;
+        self.position += skipped;
+        if self.position < position {
+            return Err(PackError::Truncated(position, self.position));
+        }
+
+        Ok(())
+    }
+
+    /// Like [`Read::read_exact`], but tells how much of the pack there was when it's truncated.
+    fn fill(&mut self, mut buf: &mut [u8]) -> error::Result<()> {
+        while !buf.is_empty() {
+            let n = match self.stream.read(buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                return Err(PackError::Truncated(self.position + buf.len() as u64, self.position));
+            }
+
+            self.position += n as u64;
+            buf = &mut buf[n..];
+        }
+
+        Ok(())
+    }
+}
+
+/// An entry which is read from a [`PackStreamReader`]. Reading it reads
+/// its contents, which are empty for directories.
+pub struct StreamEntry<'r, R> {
+    name: String,
+    metadata: EntryMetadata,
+    contents: Contents<'r, R>,
+}
+
+enum Contents<'r, R> {
+    Stored(Stored<'r, R>),
+    /// stored bytes which are decompressed while they're read
+    Decoded {
+        stored: Stored<'r, R>,
+        decoder: Box<dyn Decoder>,
+        /// stored bytes which were read, of which the ones from `start` weren't decompressed yet
+        input: Vec<u8>,
+        start: usize,
+        /// number of bytes which are still to be decompressed
+        remaining: u64,
+    },
+    Buffered(Cursor<Vec<u8>>),
+}
+
+/// The stored bytes of an entry, which are read straight from the stream
+/// and checked after the last one
+struct Stored<'r, R> {
+    name: String,
+    reader: &'r mut PackStreamReader<R>,
+    remaining: u64,
+    hasher: crc32fast::Hasher,
+    checksum: u32,
+}
+
+impl<R> StreamEntry<'_, R> {
+    fn buffered(name: String, e: TocEntry, attributes: Attributes, contents: Vec<u8>) -> Self {
+        Self {
+            name,
+            metadata: EntryMetadata {
+                len: e.size,
+                attributes,
+                is_dir: e.offset == DIRECTORY,
+            },
+            contents: Contents::Buffered(Cursor::new(contents)),
+        }
+    }
+
+    /// The normalized name of the entry in the pack
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn metadata(&self) -> EntryMetadata {
+        self.metadata
+    }
+}
+
+impl<R: Read> Read for StreamEntry<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (stored, decoder, input, start, remaining) = match &mut self.contents {
+            Contents::Stored(stored) => return stored.read(buf),
+            Contents::Decoded { stored, decoder, input, start, remaining } => (stored, decoder, input, start, remaining),
+            Contents::Buffered(contents) => return contents.read(buf),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if *start == input.len() && stored.remaining > 0 {
+                input.resize(DECODE_CHUNK_SIZE.min(usize::try_from(stored.remaining).unwrap_or(usize::MAX)), 0);
+                let n = stored.read(input)?;
+                input.truncate(n);
+                *start = 0;
+            }
+
+            let (consumed, produced) = decoder.decode(&input[*start..], buf)?;
+            *start += consumed;
+            if produced > 0 {
+                // the codec has to decompress exactly as many bytes as were compressed
+                if produced as u64 > *remaining {
+                    return Err(PackError::InvalidEntry.into());
+                }
+                *remaining -= produced as u64;
+                return Ok(produced);
+            }
+
+            if consumed == 0 {
+                // the decoder is done, the rest of the stored bytes are only read to check them
+                if *start < input.len() {
+                    *start = input.len();
+                } else if stored.remaining == 0 {
+                    break;
+                }
+            }
+        }
+
+        if *remaining > 0 {
+            return Err(PackError::InvalidEntry.into());
+        }
+        Ok(0)
+    }
+}
+
+impl<R: Read> Read for Stored<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let max = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.reader.stream.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(PackError::Truncated(self.reader.position + self.remaining, self.reader.position).into());
+        }
+
+        self.reader.position += n as u64;
+        self.remaining -= n as u64;
+        self.hasher.update(&buf[..n]);
+        if self.remaining == 0 && self.hasher.clone().finalize() != self.checksum {
+            return Err(PackError::Corrupted(vec![self.name.clone()]).into());
+        }
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+    use crate::{BackPack, PackError, RawFile};
+    use crate::pack::backpack::{TocEntry, ENTRY_MAGIC};
+    use crate::pack::{PackStreamReader, PackWriter, PACK_HEADER_SIZE};
+
+    #[test]
+    fn test_empty_files_before_contents() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named("", "z.txt")?;
+        bp.add_file_named("hello", "a.txt")?;
+        let mut file = bp.close()?;
+
+        let mut pack = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut pack)?;
+
+        let mut reader = PackStreamReader::new(pack.as_slice())?;
+        let mut entries = Vec::new();
+        while let Some(mut entry) = reader.next_entry()? {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            entries.push((entry.name().to_string(), contents));
+        }
+        assert_eq!(entries, [("z.txt".to_string(), String::new()), ("a.txt".to_string(), "hello".to_string())]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overflowing_header() -> Result<(), PackError> {
+        let mut writer = PackWriter::create(RawFile::in_memory("test.bp"))?;
+        writer.add_file_named("hello".as_bytes(), "a")?;
+        let mut file = writer.finish()?;
+
+        let mut pack = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut pack)?;
+
+        // the length in the header of "a" reaches past the end of what can be addressed
+        let record = PACK_HEADER_SIZE as usize + ENTRY_MAGIC.len();
+        let record_end = record + TocEntry::SIZE + 1;
+        pack[record + 11..record + 19].copy_from_slice(&u64::MAX.to_le_bytes());
+        let checksum = crc32fast::hash(&pack[record..record_end]);
+        pack[record_end..record_end + 4].copy_from_slice(&checksum.to_le_bytes());
+
+        let mut reader = PackStreamReader::new(pack.as_slice())?;
+        assert!(matches!(reader.next_entry(), Err(PackError::CorruptedToc(PACK_HEADER_SIZE))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::{error, RawFile};
+use crate::error::PackError;
+use crate::error::PackError::{Closed, NoName};
+use crate::pack::backpack::{BackPack, TocEntry, DATA_SIZE_POSITION};
+use crate::pack::codec::{self, STORE};
+use crate::pack::crypto::Cipher;
+#[cfg(feature = "encryption")]
+use crate::pack::crypto::KeyProvider;
+use crate::pack::metadata::Attributes;
+use crate::pack::path::{ancestors, normalize, normalize_entry};
+use crate::pack::{PACK_HEADER_SIZE, TOC_SIZE};
+
+/// Writes a new pack by streaming the contents of files straight into the backing file
+/// as they're added, followed by the table of contents when the pack is finished. Unlike
+/// [`BackPack`], which keeps files in memory until it's flushed, memory use doesn't grow
+/// with the size of the files, so very large packs can be created. Files can't be
+/// changed or removed once they're written.
+///
+/// Since files are compressed while they're written, files which don't get smaller
+/// by compressing them are stored compressed anyway.
+///
+/// Every file and directory is preceded by a header describing it, so the pack can be
+/// read from a stream with [`PackStreamReader`](crate::pack::PackStreamReader). Encrypted
+/// packs don't have these headers, since they'd reveal the names of the files.
+///
+/// ```rust
+/// # use std::io::Read;
+/// # use backpack::RawFile;
+/// # use backpack::BackPack;
+/// # use backpack::PackError;
+/// # use backpack::pack::PackWriter;
+///
+/// # fn main() -> Result<(), PackError> {
+///     let mut writer = PackWriter::create(RawFile::in_memory("test.bp"))?;
+///     writer.add_file_named("hello".as_bytes(), "hello.txt")?;
+///     writer.add_file_named(std::io::repeat(0).take(1 << 20), "zeros.bin")?;
+///     let file = writer.finish()?;
+///
+///     let bp = BackPack::open(file)?;
+///     assert_eq!(&*bp.get_file("hello.txt")?.get_bytes(), b"hello");
+///     assert_eq!(bp.metadata("zeros.bin")?.len(), 1 << 20);
+/// #   bp.close()?;
+/// #   Ok(())
+/// # }
+/// ```
+pub struct PackWriter<'f, 'backpack> {
+    file: Option<RawFile<'f, 'backpack>>,
+    /// toc entries of the files which were written
+    files: BTreeMap<String, (TocEntry, Attributes)>,
+    directories: BTreeSet<String>,
+    data_size: u64,
+    default_codec: u16,
+    /// only present when the pack is encrypted
+    cipher: Option<Cipher>,
+}
+
+impl<'f, 'backpack> PackWriter<'f, 'backpack> {
+    /// Start writing a new pack into `backing`. Existing contents of the file are overwritten.
+    pub fn create<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<Self> {
+        Self::create_with_cipher(backing, None)
+    }
+
+    /// Same as [`create`](PackWriter::create), but encrypts the pack like
+    /// [`BackPack::create_encrypted`]. Files are encrypted in chunks while
+    /// they're written, so they aren't read into memory either.
+    #[cfg(feature = "encryption")]
+    pub fn create_encrypted<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>, keys: impl KeyProvider) -> error::Result<Self> {
+        Self::create_with_cipher(backing, Some(Cipher::new(&keys)?))
+    }
+
+    fn create_with_cipher<E: Into<PackError>>(backing: impl TryInto<RawFile<'f, 'backpack>, Error=E>, cipher: Option<Cipher>) -> error::Result<Self> {
+        let mut file = backing.try_into().map_err(Into::into)?;
+
+        // until the pack is finished, its header describes an empty pack
+        file.seek(SeekFrom::Start(0))?;
+        BackPack::write_headers(&mut file, 0, &[])?;
+
+        Ok(Self {
+            file: Some(file),
+            files: BTreeMap::new(),
+            directories: BTreeSet::new(),
+            data_size: 0,
+            default_codec: STORE,
+            cipher,
+        })
+    }
+
+    /// Sets the codec files are compressed with, see [`BackPack::set_default_codec`].
+    pub fn set_default_codec(&mut self, codec: u16) -> error::Result<()> {
+        codec::get_codec(codec)?;
+        self.default_codec = codec;
+        Ok(())
+    }
+
+    /// Write a file to the pack, with the name and attributes of `f`.
+    pub fn add_file<E: Into<PackError>>(&mut self, f: impl TryInto<RawFile<'f, 'backpack>, Error=E>) -> error::Result<()> {
+        let f = f.try_into().map_err(Into::<PackError>::into)?;
+        let name = f.name().ok_or(NoName)?.to_path_buf();
+        // files which don't have metadata are added without it
+        let attributes = f.metadata().map(|m| m.attributes).unwrap_or_default();
+
+        self.write_file(&name, f, attributes)
+    }
+
+    /// Write a file named `name` to the pack, of which the contents are read from `contents`.
+    pub fn add_file_named(&mut self, contents: impl Read, name: impl AsRef<Path>) -> error::Result<()> {
+        self.write_file(name.as_ref(), contents, Attributes::default())
+    }
+
+    fn write_file(&mut self, name: &Path, contents: impl Read, attributes: Attributes) -> error::Result<()> {
+        let name = normalize_entry(name)?;
+        self.check_file_name(&name)?;
+        let file = self.file.as_mut().ok_or(Closed)?;
+
+        // a file which failed to be written is overwritten by the next one
+        let start = PACK_HEADER_SIZE + self.data_size;
+        file.seek(SeekFrom::Start(start))?;
+        let toc_entry = match &self.cipher {
+            Some(cipher) => {
+                let mut input = Tally::new(contents);
+                let mut output = cipher.chunk_encryptor(Tally::new(&mut *file));
+                codec::compress_to(self.default_codec, &mut input, &mut output)?;
+                let (output, nonce) = output.finish()?;
+
+                TocEntry {
+                    offset: self.data_size,
+                    length: output.len,
+                    codec: self.default_codec,
+                    size: input.len,
+                    checksum: output.hasher.finalize(),
+                    nonce: Some(nonce),
+                }
+            }
+            None => {
+                // the header is written again once the contents are known, its size doesn't change
+                let header_size = BackPack::inline_header(&name, &TocEntry::TOMBSTONE, &attributes)?.len() as u64;
+                file.write_all(&vec![0; header_size as usize])?;
+
+                let mut input = Tally::new(contents);
+                let mut output = Tally::new(&mut *file);
+                codec::compress_to(self.default_codec, &mut input, &mut output)?;
+
+                let toc_entry = TocEntry {
+                    offset: self.data_size + header_size,
+                    length: output.len,
+                    codec: self.default_codec,
+                    size: input.len,
+                    checksum: output.hasher.finalize(),
+                    nonce: None,
+                };
+                file.seek(SeekFrom::Start(start))?;
+                file.write_all(&BackPack::inline_header(&name, &toc_entry, &attributes)?)?;
+                toc_entry
+            }
+        };
+
+        self.data_size = toc_entry.offset + toc_entry.length;
+        self.files.insert(name, (toc_entry, attributes));
+
+        Ok(())
+    }
+
+    /// Files can't be replaced once they're written, and can't be where directories are.
+    fn check_file_name(&self, name: &str) -> error::Result<()> {
+        if self.files.contains_key(name) {
+            return Err(PackError::FileExists(name.into()));
+        }
+
+        let prefix = format!("{}/", name);
+        let has_children = self.files.range(prefix.clone()..).next()
+            .is_some_and(|(child, _)| child.starts_with(&prefix));
+        if has_children || self.directories.contains(name) {
+            return Err(PackError::IsADirectory(name.into()));
+        }
+
+        match ancestors(name).find(|dir| self.files.contains_key(*dir)) {
+            Some(file) => Err(PackError::NotADirectory(file.into())),
+            None => Ok(()),
+        }
+    }
+
+    /// Create a directory and all of its parents, see [`BackPack::create_dir_all`].
+    pub fn create_dir_all(&mut self, path: impl AsRef<Path>) -> error::Result<()> {
+        let name = normalize(path.as_ref())?;
+        if name.is_empty() {
+            return Ok(());
+        }
+
+        for dir in ancestors(&name).chain(std::iter::once(name.as_str())) {
+            if self.files.contains_key(dir) {
+                return Err(PackError::NotADirectory(dir.into()));
+            }
+            if self.directories.contains(dir) {
+                continue;
+            }
+
+            if self.cipher.is_none() {
+                let file = self.file.as_mut().ok_or(Closed)?;
+                let header = BackPack::inline_header(dir, &TocEntry::DIRECTORY, &Attributes::default())?;
+                file.seek(SeekFrom::Start(PACK_HEADER_SIZE + self.data_size))?;
+                file.write_all(&header)?;
+                self.data_size += header.len() as u64;
+            }
+            self.directories.insert(dir.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Write the table of contents after the files, after which the pack
+    /// can be opened like any other pack. Returns the backing file.
+    pub fn finish(mut self) -> error::Result<RawFile<'f, 'backpack>> {
+        self.write_toc()?;
+
+        let mut file = self.file.take().ok_or(Closed)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+
+    fn write_toc(&mut self) -> error::Result<()> {
+        let file = self.file.as_mut().ok_or(Closed)?;
+
+        let toc = self.files.iter()
+            .map(|(name, entry)| (name.clone(), *entry))
+            .chain(BackPack::directory_toc(&self.directories, &HashMap::new()))
+            .collect();
+        let first_toc_offset = PACK_HEADER_SIZE + self.data_size;
+        let toc_blocks = BackPack::create_toc(&toc, first_toc_offset, 0, self.cipher.as_ref())?;
+
+        file.seek(SeekFrom::Start(first_toc_offset))?;
+        for block in &toc_blocks {
+            file.write_all(block)?;
+        }
+        file.set_len(first_toc_offset + toc_blocks.len() as u64 * TOC_SIZE as u64)?;
+
+        // only refer to the toc after it's completely written.
+        // the offset of the first toc block follows the data size.
+        file.seek(SeekFrom::Start(DATA_SIZE_POSITION))?;
+        file.write_all(&self.data_size.to_le_bytes())?;
+        if !toc_blocks.is_empty() {
+            file.write_all(&first_toc_offset.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PackWriter<'_, '_> {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            log::warn!("dropping unfinished pack writer may panic. Attempting best-effort finish.");
+            if let Err(e) = self.write_toc() {
+                panic!("failed to finish pack. pack likely corrupted. {}", e);
+            }
+        }
+    }
+}
+
+/// Counts and hashes the bytes which are read or written through it
+struct Tally<T> {
+    inner: T,
+    len: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl<T> Tally<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            len: 0,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for Tally<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.len += n as u64;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Tally<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,179 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use crate::error::{PackError, Result};
+
+/// Identifier of the [`Store`] codec
+pub const STORE: u16 = 0;
+/// Identifier of the [`Deflate`] codec
+pub const DEFLATE: u16 = 1;
+
+/// A way to compress the contents of files in a backpack. The identifier
+/// of the codec is stored with every file, so custom codecs must be registered
+/// with [`register_codec`] before packs using them are opened.
+pub trait Codec: Send + Sync {
+    /// Identifier of the codec which is stored in the table of contents.
+    fn id(&self) -> u16;
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Compress everything read from `data` into `out`, used by [`PackWriter`](crate::pack::PackWriter).
+    /// The default implementation reads all of `data` into memory first, codecs which can
+    /// compress a stream should override it.
+    fn compress_to(&self, data: &mut dyn Read, out: &mut dyn Write) -> io::Result<()> {
+        let mut buf = Vec::new();
+        data.read_to_end(&mut buf)?;
+        out.write_all(&self.compress(&buf)?)
+    }
+
+    /// Decompress `data`, which was compressed from `size` bytes.
+    fn decompress(&self, data: &[u8], size: u64) -> io::Result<Vec<u8>>;
+
+    /// A decoder which decompresses data compressed from `size` bytes piece by piece, used by
+    /// [`PackStreamReader`](crate::pack::PackStreamReader). Without one, which is the default,
+    /// the compressed data is read into memory first.
+    fn decoder(&self, _size: u64) -> Option<Box<dyn Decoder>> {
+        None
+    }
+}
+
+/// Decompresses data which is passed to it piece by piece, see [`Codec::decoder`].
+pub trait Decoder: Send {
+    /// Decompress the start of `input` into `output`, returning how many bytes were consumed and
+    /// how many were produced. Returns `(0, 0)` when everything was decompressed, and only when
+    /// `input` or `output` is empty otherwise. Input which wasn't consumed is passed again.
+    fn decode(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)>;
+}
+
+/// Stores files as-is.
+pub struct Store;
+
+impl Codec for Store {
+    fn id(&self) -> u16 {
+        STORE
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    fn compress_to(&self, data: &mut dyn Read, out: &mut dyn Write) -> io::Result<()> {
+        io::copy(data, out)?;
+        Ok(())
+    }
+
+    fn decompress(&self, data: &[u8], _size: u64) -> io::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+/// Compresses files with deflate.
+#[cfg(feature = "deflate")]
+pub struct Deflate;
+
+#[cfg(feature = "deflate")]
+impl Codec for Deflate {
+    fn id(&self) -> u16 {
+        DEFLATE
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    fn compress_to(&self, data: &mut dyn Read, out: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = flate2::write::DeflateEncoder::new(out, flate2::Compression::default());
+        io::copy(data, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decompress(&self, data: &[u8], size: u64) -> io::Result<Vec<u8>> {
+        let mut res = Vec::with_capacity(size as usize);
+        flate2::read::DeflateDecoder::new(data).read_to_end(&mut res)?;
+        Ok(res)
+    }
+
+    fn decoder(&self, _size: u64) -> Option<Box<dyn Decoder>> {
+        Some(Box::new(flate2::Decompress::new(false)))
+    }
+}
+
+#[cfg(feature = "deflate")]
+impl Decoder for flate2::Decompress {
+    fn decode(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)> {
+        let (total_in, total_out) = (self.total_in(), self.total_out());
+        self.decompress(input, output, flate2::FlushDecompress::None)?;
+        Ok(((self.total_in() - total_in) as usize, (self.total_out() - total_out) as usize))
+    }
+}
+
+lazy_static! {
+    static ref CODECS: RwLock<HashMap<u16, Arc<dyn Codec>>> = {
+        let mut codecs: HashMap<u16, Arc<dyn Codec>> = HashMap::new();
+        codecs.insert(STORE, Arc::new(Store));
+        #[cfg(feature = "deflate")]
+        codecs.insert(DEFLATE, Arc::new(Deflate));
+
+        RwLock::new(codecs)
+    };
+}
+
+/// Make a codec available to all backpacks. Replaces any
+/// codec which was registered with the same identifier.
+pub fn register_codec(codec: impl Codec + 'static) {
+    CODECS.write().insert(codec.id(), Arc::new(codec));
+}
+
+pub fn get_codec(id: u16) -> Result<Arc<dyn Codec>> {
+    CODECS.read()
+        .get(&id)
+        .cloned()
+        .ok_or(PackError::UnknownCodec(id))
+}
+
+/// Compress `data` with the codec `id`. When that doesn't make the data any
+/// smaller, it's stored instead. Returns the codec that was actually used.
+pub(crate) fn compress(id: u16, data: &[u8]) -> Result<(u16, Cow<'_, [u8]>)> {
+    if id == STORE {
+        return Ok((STORE, data.into()));
+    }
+
+    let compressed = get_codec(id)?.compress(data)?;
+    if compressed.len() < data.len() {
+        Ok((id, compressed.into()))
+    } else {
+        Ok((STORE, data.into()))
+    }
+}
+
+/// Compress everything read from `data` into `out` with the codec `id`. Unlike
+/// [`compress`], this can't fall back to storing data which doesn't get smaller.
+pub(crate) fn compress_to(id: u16, data: &mut dyn Read, out: &mut dyn Write) -> Result<()> {
+    get_codec(id)?.compress_to(data, out)?;
+    Ok(())
+}
+
+pub(crate) fn decompress(id: u16, data: Vec<u8>, size: u64) -> Result<Vec<u8>> {
+    if id == STORE {
+        return Ok(data);
+    }
+
+    let res = get_codec(id)?.decompress(&data, size)?;
+    if res.len() as u64 != size {
+        return Err(PackError::InvalidEntry);
+    }
+
+    Ok(res)
+}
+
+/// The decoder of the codec `id`, see [`Codec::decoder`].
+pub(crate) fn decoder(id: u16, size: u64) -> Result<Option<Box<dyn Decoder>>> {
+    Ok(get_codec(id)?.decoder(size))
+}
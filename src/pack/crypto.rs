@@ -0,0 +1,240 @@
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::io::Write;
+use crate::error;
+
+/// Size of the nonces stored with encrypted entries and toc blocks
+pub(crate) const NONCE_SIZE: usize = 24;
+/// Size of the authentication tag which is appended to encrypted data
+pub(crate) const TAG_SIZE: usize = 16;
+/// Size of the value encrypted toc blocks store to recognize the key they were encrypted with
+pub(crate) const KEY_CHECK_SIZE: usize = 16;
+
+/// Size of the chunks the contents of files are encrypted in, so
+/// they can be encrypted while they're written, see [`ChunkEncryptor`]
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+pub(crate) type Nonce = [u8; NONCE_SIZE];
+
+/// Supplies the key an encrypted backpack is encrypted with, see
+/// [`BackPack::create_encrypted`](crate::BackPack::create_encrypted).
+#[cfg(feature = "encryption")]
+pub trait KeyProvider {
+    /// The 256 bit key of the pack. Implementations which get the key from
+    /// somewhere else, like a key management service, can fail here.
+    fn key(&self) -> error::Result<[u8; 32]>;
+}
+
+#[cfg(feature = "encryption")]
+impl KeyProvider for [u8; 32] {
+    fn key(&self) -> error::Result<[u8; 32]> {
+        Ok(*self)
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<K: KeyProvider + ?Sized> KeyProvider for &K {
+    fn key(&self) -> error::Result<[u8; 32]> {
+        (**self).key()
+    }
+}
+
+/// Encrypts the contents of a pack with XChaCha20-Poly1305. The nonces are
+/// large enough to be chosen randomly for every entry and toc block.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+    key_check: [u8; KEY_CHECK_SIZE],
+}
+
+/// Packs can't be encrypted without the `encryption` feature.
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub enum Cipher {}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    /// Associated data of the key check value, so it's never the tag of stored data
+    const KEY_CHECK_AAD: &'static [u8] = b"backpack key check";
+
+    pub(crate) fn new(keys: &impl KeyProvider) -> error::Result<Self> {
+        let aead = XChaCha20Poly1305::new(&keys.key()?.into());
+
+        // the tag of an empty message, which identifies the key without revealing it
+        let tag = aead.encrypt(&XNonce::default(), Payload { msg: &[], aad: Self::KEY_CHECK_AAD })
+            .expect("an empty message can always be encrypted");
+        let mut key_check = [0; KEY_CHECK_SIZE];
+        key_check.copy_from_slice(&tag);
+
+        Ok(Self { aead, key_check })
+    }
+
+    pub(crate) fn key_check(&self) -> &[u8; KEY_CHECK_SIZE] {
+        &self.key_check
+    }
+
+    /// Encrypt `data` with a new random nonce. The result is [`TAG_SIZE`] bytes
+    /// longer than `data`, and can only be decrypted with the same `aad`.
+    pub(crate) fn encrypt(&self, data: &[u8], aad: &[u8]) -> error::Result<(Vec<u8>, Nonce)> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = self.aead.encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "data too large to encrypt"))?;
+
+        Ok((encrypted, nonce.into()))
+    }
+
+    /// Decrypt `data`, or `None` when it wasn't encrypted with this key, `nonce` and `aad`.
+    pub(crate) fn decrypt(&self, nonce: &Nonce, data: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        self.aead.decrypt(XNonce::from_slice(nonce), Payload { msg: data, aad }).ok()
+    }
+
+    /// Start encrypting data in chunks into `out`, with a new random nonce
+    pub(crate) fn chunk_encryptor<W: Write>(&self, out: W) -> ChunkEncryptor<'_, W> {
+        ChunkEncryptor {
+            cipher: self,
+            nonce: XChaCha20Poly1305::generate_nonce(&mut OsRng).into(),
+            out,
+            index: 0,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn encrypt_chunk(&self, nonce: &Nonce, chunk: &[u8], aad: &[u8]) -> error::Result<Vec<u8>> {
+        self.aead.encrypt(XNonce::from_slice(nonce), Payload { msg: chunk, aad })
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "data too large to encrypt").into())
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    pub(crate) fn key_check(&self) -> &[u8; KEY_CHECK_SIZE] {
+        match *self {}
+    }
+
+    pub(crate) fn encrypt(&self, _data: &[u8], _aad: &[u8]) -> error::Result<(Vec<u8>, Nonce)> {
+        match *self {}
+    }
+
+    pub(crate) fn decrypt(&self, _nonce: &Nonce, _data: &[u8], _aad: &[u8]) -> Option<Vec<u8>> {
+        match *self {}
+    }
+
+    pub(crate) fn chunk_encryptor<W: Write>(&self, _out: W) -> ChunkEncryptor<'_, W> {
+        match *self {}
+    }
+
+    fn encrypt_chunk(&self, _nonce: &Nonce, _chunk: &[u8], _aad: &[u8]) -> error::Result<Vec<u8>> {
+        match *self {}
+    }
+}
+
+impl Cipher {
+    /// Encrypt `data` in chunks with a new random nonce, like [`ChunkEncryptor`] does.
+    pub(crate) fn encrypt_chunked(&self, data: &[u8]) -> error::Result<(Vec<u8>, Nonce)> {
+        let mut encryptor = self.chunk_encryptor(Vec::new());
+        encryptor.write_all(data)?;
+        encryptor.finish()
+    }
+
+    /// Decrypt data which was encrypted in chunks with `nonce`, or `None` when it wasn't
+    /// encrypted with this key and nonce, or chunks were removed, reordered or changed.
+    pub(crate) fn decrypt_chunked(&self, nonce: &Nonce, data: &[u8]) -> Option<Vec<u8>> {
+        if data.is_empty() {
+            return None;
+        }
+
+        let chunks = data.chunks(CHUNK_SIZE + TAG_SIZE);
+        let count = chunks.len();
+        let mut res = Vec::with_capacity(data.len());
+        for (index, chunk) in chunks.enumerate() {
+            let (nonce, aad) = chunk_nonce(nonce, index as u64, index + 1 == count);
+            res.extend_from_slice(&self.decrypt(&nonce, chunk, &aad)?);
+        }
+
+        Some(res)
+    }
+}
+
+/// Every chunk has its own nonce, derived from the nonce of the data by its index. The
+/// associated data marks the last chunk, so data can't be cut short at a chunk boundary.
+fn chunk_nonce(nonce: &Nonce, index: u64, last: bool) -> (Nonce, [u8; 1]) {
+    let mut chunk_nonce = *nonce;
+    for (n, i) in chunk_nonce[NONCE_SIZE - 8..].iter_mut().zip(index.to_le_bytes()) {
+        *n ^= i;
+    }
+    (chunk_nonce, [last as u8])
+}
+
+/// Encrypts the data written to it in chunks of [`CHUNK_SIZE`] bytes, each of which is
+/// followed by its authentication tag. Only one chunk is kept in memory at a time.
+pub(crate) struct ChunkEncryptor<'c, W> {
+    cipher: &'c Cipher,
+    nonce: Nonce,
+    out: W,
+    index: u64,
+    chunk: Vec<u8>,
+}
+
+impl<W: Write> ChunkEncryptor<'_, W> {
+    fn write_chunk(&mut self, last: bool) -> error::Result<()> {
+        let (nonce, aad) = chunk_nonce(&self.nonce, self.index, last);
+        self.out.write_all(&self.cipher.encrypt_chunk(&nonce, &self.chunk, &aad)?)?;
+        self.index += 1;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Encrypt the last chunk, returning the output and the nonce it has to be decrypted with
+    pub(crate) fn finish(mut self) -> error::Result<(W, Nonce)> {
+        self.write_chunk(true)?;
+        Ok((self.out, self.nonce))
+    }
+}
+
+impl<W: Write> Write for ChunkEncryptor<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // full chunks are only written once more data follows, since the last one is marked
+        if self.chunk.len() == CHUNK_SIZE && !buf.is_empty() {
+            self.write_chunk(false)?;
+        }
+
+        let n = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use crate::pack::crypto::{Cipher, CHUNK_SIZE, TAG_SIZE};
+
+    #[test]
+    fn test_chunks() -> crate::Result<()> {
+        let cipher = Cipher::new(&[7; 32])?;
+        for size in [0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 1] {
+            let data = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+            let (encrypted, nonce) = cipher.encrypt_chunked(&data)?;
+            assert_eq!(encrypted.len(), size + size.div_ceil(CHUNK_SIZE).max(1) * TAG_SIZE);
+            assert_eq!(cipher.decrypt_chunked(&nonce, &encrypted), Some(data));
+        }
+
+        // chunks can't be dropped or reordered
+        let data = vec![1; 2 * CHUNK_SIZE + 1];
+        let (encrypted, nonce) = cipher.encrypt_chunked(&data)?;
+        let chunk = CHUNK_SIZE + TAG_SIZE;
+        assert_eq!(cipher.decrypt_chunked(&nonce, &encrypted[..2 * chunk]), None);
+        assert_eq!(cipher.decrypt_chunked(&nonce, &encrypted[chunk..]), None);
+        let reordered = [&encrypted[chunk..2 * chunk], &encrypted[..chunk], &encrypted[2 * chunk..]].concat();
+        assert_eq!(cipher.decrypt_chunked(&nonce, &reordered), None);
+        assert_eq!(cipher.decrypt_chunked(&nonce, &[]), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::path::Path;
+use crate::pack::in_memory::InMemoryFile;
+use crate::pack::lazy::LazyFile;
+use crate::pack::metadata::EntryMetadata;
+use crate::pack::slice::PackSlice;
+use crate::BackPack;
+
+/// A file in a backpack, as returned by [`BackPack::entries`].
+pub struct Entry<'f, 'backpack> {
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    pub(crate) key: (u64, u64),
+
+    pub(crate) pack: &'f BackPack<'f, 'backpack>,
+}
+
+impl<'f, 'backpack> Entry<'f, 'backpack> {
+    /// The full name of the file in the backpack
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        Path::new(&self.name)
+    }
+
+    /// Size of the contents of the file, at the time the entry was listed
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn metadata(&self) -> EntryMetadata {
+        self.pack.metadata_of(&self.name, self.key)
+    }
+
+    /// Get a handle to the file, like [`BackPack::get_file`].
+    pub fn open(&self) -> InMemoryFile<'f, 'backpack> {
+        InMemoryFile::Packed {
+            name: self.path().to_path_buf(),
+            data: PackSlice::new(self.name.clone(), self.key.0, self.key.1, self.pack),
+        }
+    }
+
+    /// Get a handle to the file which reads its contents lazily, like [`BackPack::get_file_lazy`].
+    pub fn open_lazy(&self) -> LazyFile<'f, 'backpack> {
+        LazyFile::new(PackSlice::new(self.name.clone(), self.key.0, self.key.1, self.pack))
+    }
+}
+
+/// An entry in a directory of a backpack, as returned by [`BackPack::read_dir`].
+/// Names in a backpack form a hierarchy separated by `/`.
+pub enum DirEntry<'f, 'backpack> {
+    File(Entry<'f, 'backpack>),
+    /// A directory, which was created explicitly and is stored as a directory entry in
+    /// the table of contents, or which contains at least one file. Holds the full name
+    /// of the directory.
+    Directory(String),
+}
+
+impl DirEntry<'_, '_> {
+    /// The full name of the entry in the backpack
+    pub fn name(&self) -> &str {
+        match self {
+            DirEntry::File(f) => f.name(),
+            DirEntry::Directory(name) => name,
+        }
+    }
+
+    /// The last component of the name of the entry
+    pub fn file_name(&self) -> &str {
+        let name = self.name();
+        name.rsplit('/').next().unwrap_or(name)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self, DirEntry::Directory(_))
+    }
+}
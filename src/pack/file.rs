@@ -1,7 +1,7 @@
-use std::fs::Metadata;
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use crate::pack::in_memory::InMemoryFile;
+use crate::pack::metadata::EntryMetadata;
 use crate::error::Result;
 
 pub enum RawFile<'f, 'backpack> {
@@ -71,11 +71,36 @@ impl<'f, 'backpack> RawFile<'f, 'backpack> {
 
     pub fn current_offset(&mut self) -> Result<u64> {
         match self {
-            RawFile::Disk { file, .. } => file.seek(SeekFrom::Current(0)).map_err(Into::into),
+            RawFile::Disk { file, .. } => file.stream_position().map_err(Into::into),
             RawFile::InMemory(f, ..) => Ok(f.current_offset()),
         }
     }
 
+    /// Fill `buf` with the bytes starting at `offset`, without
+    /// using (or moving) the cursor of the file.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        match self {
+            RawFile::Disk { file, .. } => {
+                #[cfg(unix)]
+                std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)?;
+
+                #[cfg(windows)]
+                {
+                    let mut filled = 0;
+                    while filled < buf.len() {
+                        match std::os::windows::fs::FileExt::seek_read(file, &mut buf[filled..], offset + filled as u64)? {
+                            0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                            n => filled += n,
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+            RawFile::InMemory(f) => f.read_exact_at(buf, offset),
+        }
+    }
+
     pub fn sync_all(&self) -> Result<()> {
         match self {
             RawFile::Disk { file, .. } => file.sync_all().map_err(Into::into),
@@ -90,10 +115,10 @@ impl<'f, 'backpack> RawFile<'f, 'backpack> {
         }
     }
 
-    pub fn metadata(&self) -> Result<Metadata> {
+    pub fn metadata(&self) -> Result<EntryMetadata> {
         match self {
-            RawFile::InMemory(..) => todo!(),
-            RawFile::Disk { file, .. } => file.metadata().map_err(Into::into),
+            RawFile::InMemory(f) => f.metadata(),
+            RawFile::Disk { file, .. } => Ok((&file.metadata()?).into()),
         }
     }
 
@@ -1,8 +1,8 @@
 use std::path::{Path, PathBuf};
 use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
-use parking_lot::RwLockReadGuard;
 use crate::error;
 use crate::pack::maybe_ref::MaybeRef;
+use crate::pack::metadata::EntryMetadata;
 use crate::pack::slice::PackSlice;
 
 impl InMemoryFile<'_, '_> {
@@ -47,18 +47,53 @@ impl InMemoryFile<'_, '_> {
                 Ok(())
             }
             InMemoryFile::Packed { data, ..} => {
-                data.resize(size);
-                Ok(())
+                data.resize(size)
             }
         }
     }
 
-    pub fn get_bytes(&self) -> MaybeRef<[u8]> {
-        match self {
+    /// Get the contents of the file.
+    ///
+    /// # Panics
+    /// When the file is stored in a partially parsed backpack, and
+    /// reading it from the backing file fails. See [`try_get_bytes`](InMemoryFile::try_get_bytes).
+    pub fn get_bytes(&self) -> MaybeRef<'_, [u8]> {
+        self.try_get_bytes().expect("failed to read file from backpack")
+    }
+
+    pub fn try_get_bytes(&self) -> error::Result<MaybeRef<'_, [u8]>> {
+        Ok(match self {
             InMemoryFile::Named { data, .. } => data.get_ref().as_slice().into(),
-            InMemoryFile::Packed { data, .. } => RwLockReadGuard::map(data.get_bytes().read(), |i| i.as_slice()).into(),
+            InMemoryFile::Packed { data, .. } => data.get_bytes()?,
             InMemoryFile::Unnamed { data, .. } => data.get_ref().as_slice().into(),
-        }
+        })
+    }
+
+    /// Fill `buf` with the bytes starting at `offset`, without
+    /// using (or moving) the cursor of the file.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> error::Result<()> {
+        let bytes = self.try_get_bytes()?;
+        let contents = usize::try_from(offset).ok()
+            .and_then(|start| bytes.get(start..start.checked_add(buf.len())?))
+            .ok_or_else(|| std::io::Error::from(ErrorKind::UnexpectedEof))?;
+
+        buf.copy_from_slice(contents);
+        Ok(())
+    }
+
+    /// Files which only live in memory only have a length. Packed files
+    /// have the metadata which was stored with them in the backpack.
+    pub fn metadata(&self) -> error::Result<EntryMetadata> {
+        Ok(match self {
+            InMemoryFile::Named { data, .. } |
+            InMemoryFile::Unnamed { data } => EntryMetadata {
+                len: data.get_ref().len() as u64,
+                ..Default::default()
+            },
+            InMemoryFile::Packed { data, .. } => {
+                data.pack.metadata_of(data.name(), data.identifier())
+            }
+        })
     }
 
     pub fn with_name(self, s: impl AsRef<Path>) -> Self {
@@ -84,16 +119,27 @@ impl InMemoryFile<'_, '_> {
         }
     }
 
+    /// Clones of packed files refer to the same file in the backpack.
+    /// Files which only live in memory are copied.
     pub fn try_clone(&self) -> error::Result<Self> {
         match self {
-            InMemoryFile::Named { .. } => todo!(),
+            InMemoryFile::Named { name, data } => {
+                Ok(InMemoryFile::Named {
+                    name: name.clone(),
+                    data: data.clone(),
+                })
+            }
             InMemoryFile::Packed { data, name } => {
                 Ok(InMemoryFile::Packed {
                     name: name.clone(),
                     data: data.clone(),
                 })
             }
-            InMemoryFile::Unnamed { .. } => todo!(),
+            InMemoryFile::Unnamed { data } => {
+                Ok(InMemoryFile::Unnamed {
+                    data: data.clone(),
+                })
+            }
         }
     }
 }
@@ -149,9 +195,7 @@ impl Write for InMemoryFile<'_, '_> {
         match self {
             InMemoryFile::Named { data, .. } |
             InMemoryFile::Unnamed { data } => data.write(buf),
-            InMemoryFile::Packed { .. } => {
-                Err(std::io::Error::new(ErrorKind::PermissionDenied, "can't write to file backed by backpack"))
-            }
+            InMemoryFile::Packed { data, .. } => data.write(buf),
         }
     }
 
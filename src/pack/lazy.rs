@@ -0,0 +1,127 @@
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use crate::error;
+use crate::pack::metadata::EntryMetadata;
+use crate::pack::slice::PackSlice;
+
+/// A read-only handle to a file in a backpack, which reads the contents straight from
+/// the backing file, without loading them into memory. Only contents which are stored
+/// without compression or encryption, and which weren't changed since the pack was last
+/// flushed, can be read this way. Contents of other files, and of files in packs opened
+/// with [`open_complete`](crate::BackPack::open_complete), are loaded into memory like
+/// [`get_file`](crate::BackPack::get_file) does.
+///
+/// Contents which are read from the backing file aren't checked against their
+/// checksum, see [`verify`](crate::BackPack::verify).
+///
+/// ```rust
+/// # use std::io::{Read, Seek, SeekFrom};
+/// # use backpack::RawFile;
+/// # use backpack::BackPack;
+/// # use backpack::PackError;
+///
+/// # fn main() -> Result<(), PackError> {
+///     let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+///     bp.add_file_named(vec![1; 1 << 20], "large.bin")?;
+///     let file = bp.close()?;
+///
+///     let bp = BackPack::open_partial(file)?;
+///     let mut f = bp.get_file_lazy("large.bin")?;
+///     f.seek(SeekFrom::End(-4096))?;
+///     let mut tail = Vec::new();
+///     f.read_to_end(&mut tail)?;
+///
+///     assert_eq!(tail, vec![1; 4096]);
+///     assert_eq!(bp.memory_bytes(), 0);
+/// #   bp.close()?;
+/// #   Ok(())
+/// # }
+/// ```
+pub struct LazyFile<'f, 'backpack> {
+    slice: PackSlice<'f, 'backpack>,
+    /// physical range of the contents in the backing file, when they're read from it directly
+    stored: Option<(u64, u64)>,
+    pos: u64,
+}
+
+impl<'f, 'backpack> LazyFile<'f, 'backpack> {
+    pub(crate) fn new(slice: PackSlice<'f, 'backpack>) -> Self {
+        let stored = slice.pack.stored_range(slice.identifier());
+        Self {
+            slice,
+            stored,
+            pos: 0,
+        }
+    }
+
+    /// Name of the file in the pack
+    pub fn name(&self) -> &str {
+        self.slice.name()
+    }
+
+    pub fn metadata(&self) -> EntryMetadata {
+        self.slice.pack.metadata_of(self.slice.name(), self.slice.identifier())
+    }
+
+    /// Whether the contents are read from the backing file, instead of from memory
+    pub fn reads_backing_file(&self) -> bool {
+        self.stored.is_some()
+    }
+
+    /// Fill `buf` with the bytes starting at `offset`, without
+    /// using (or moving) the cursor of the file.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> error::Result<()> {
+        let Some((start, end)) = self.stored else {
+            let bytes = self.slice.get_bytes()?;
+            let contents = usize::try_from(offset).ok()
+                .and_then(|start| bytes.get(start..start.checked_add(buf.len())?))
+                .ok_or_else(|| std::io::Error::from(ErrorKind::UnexpectedEof))?;
+
+            buf.copy_from_slice(contents);
+            return Ok(());
+        };
+
+        let in_range = offset.checked_add(buf.len() as u64)
+            .is_some_and(|read_end| read_end <= end - start);
+        if !in_range {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+
+        self.slice.pack.backing_file()?.read_exact_at(buf, start + offset)
+    }
+}
+
+impl Read for LazyFile<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some((start, end)) = self.stored else {
+            return self.slice.read(buf);
+        };
+
+        let remaining = (end - start).saturating_sub(self.pos);
+        let n = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        self.read_exact_at(&mut buf[..n], self.pos)?;
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for LazyFile<'_, '_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let Some((start, end)) = self.stored else {
+            return self.slice.seek(pos);
+        };
+
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (end - start, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+
+        self.pos = base.checked_add_signed(offset)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+        Ok(self.pos)
+    }
+}
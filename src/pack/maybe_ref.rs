@@ -1,10 +1,14 @@
 use std::fmt::{Debug, Formatter};
 use std::ops::Deref;
-use parking_lot::MappedRwLockReadGuard;
+use parking_lot::{MappedRwLockReadGuard, RawRwLock};
+use parking_lot::lock_api::ArcRwLockReadGuard;
 
 pub enum MaybeRef<'a, T: ?Sized> {
     Regular(&'a T),
     Ref(MappedRwLockReadGuard<'a, T>),
+    /// A guard which keeps the data it points to alive. Used for
+    /// data which can be evicted from a backpack after it's dropped.
+    Shared(ArcRwLockReadGuard<RawRwLock, Vec<u8>>, fn(&Vec<u8>) -> &T),
 }
 
 impl<T: Debug + ?Sized> Debug for MaybeRef<'_, T> {
@@ -27,6 +31,12 @@ impl<'a, T: ?Sized> From<MappedRwLockReadGuard<'a, T>> for MaybeRef<'a, T> {
     }
 }
 
+impl From<ArcRwLockReadGuard<RawRwLock, Vec<u8>>> for MaybeRef<'_, [u8]> {
+    fn from(r: ArcRwLockReadGuard<RawRwLock, Vec<u8>>) -> Self {
+        Self::Shared(r, Vec::as_slice)
+    }
+}
+
 impl<T: ?Sized> Deref for MaybeRef<'_, T> {
     type Target = T;
 
@@ -36,6 +46,7 @@ impl<T: ?Sized> Deref for MaybeRef<'_, T> {
             MaybeRef::Ref(r) => {
                 r.deref()
             }
+            MaybeRef::Shared(r, project) => project(r),
         }
     }
-}
\ No newline at end of file
+}
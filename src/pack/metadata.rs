@@ -0,0 +1,123 @@
+use std::io::{Error as IoError, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Metadata of a file, which is stored in a backpack. Unlike [`std::fs::Metadata`]
+/// it can be created for files which only live in memory.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub(crate) len: u64,
+    pub(crate) attributes: Attributes,
+    pub(crate) is_dir: bool,
+}
+
+impl EntryMetadata {
+    /// Size of the contents of the file
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+
+    /// The last modification time of the file, like [`std::fs::Metadata::modified`].
+    /// Fails when the file wasn't added from a file on disk.
+    pub fn modified(&self) -> std::io::Result<SystemTime> {
+        self.attributes.modified
+            .ok_or_else(|| IoError::new(ErrorKind::Unsupported, "modification time is not known"))
+    }
+
+    /// Unix permission bits of the file, when it was added from a file on disk on unix.
+    pub fn mode(&self) -> Option<u32> {
+        self.attributes.mode
+    }
+}
+
+impl From<&std::fs::Metadata> for EntryMetadata {
+    fn from(m: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = Some(std::os::unix::fs::PermissionsExt::mode(&m.permissions()));
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Self {
+            len: m.len(),
+            attributes: Attributes {
+                modified: m.modified().ok(),
+                mode,
+            },
+            is_dir: m.is_dir(),
+        }
+    }
+}
+
+/// The metadata stored with every file in the table of contents.
+/// The length of a file is stored with its contents instead.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Attributes {
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) mode: Option<u32>,
+}
+
+impl Attributes {
+    /// Size of the attributes in a toc entry: mtime seconds i64 | mtime nanoseconds u32 | mode u32
+    pub(crate) const SIZE: usize = 8 + 4 + 4;
+
+    /// Marks unknown modification times and modes
+    const UNKNOWN: u32 = u32::MAX;
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::SIZE] {
+        let (secs, nanos) = match self.modified.map(|m| m.duration_since(UNIX_EPOCH)) {
+            Some(Ok(after)) => (after.as_secs() as i64, after.subsec_nanos()),
+            // times before the epoch are stored as negative seconds, plus positive nanoseconds
+            Some(Err(before)) => {
+                let before = before.duration();
+                match before.subsec_nanos() {
+                    0 => (-(before.as_secs() as i64), 0),
+                    nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+                }
+            }
+            None => (0, Self::UNKNOWN),
+        };
+
+        let mut res = [0; Self::SIZE];
+        res[..8].copy_from_slice(&secs.to_le_bytes());
+        res[8..12].copy_from_slice(&nanos.to_le_bytes());
+        res[12..].copy_from_slice(&self.mode.unwrap_or(Self::UNKNOWN).to_le_bytes());
+        res
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
+        let mut secs_bytes = [0u8; 8];
+        secs_bytes.copy_from_slice(&bytes[..8]);
+        let secs = i64::from_le_bytes(secs_bytes);
+
+        let mut nanos_bytes = [0u8; 4];
+        nanos_bytes.copy_from_slice(&bytes[8..12]);
+        let nanos = u32::from_le_bytes(nanos_bytes);
+
+        let mut mode_bytes = [0u8; 4];
+        mode_bytes.copy_from_slice(&bytes[12..16]);
+        let mode = u32::from_le_bytes(mode_bytes);
+
+        let modified = match (nanos, secs) {
+            (Self::UNKNOWN, _) => None,
+            (_, 0..) => UNIX_EPOCH.checked_add(Duration::new(secs as u64, nanos)),
+            (_, _) => UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+                .and_then(|t| t.checked_add(Duration::from_nanos(nanos as u64))),
+        };
+
+        Self {
+            modified,
+            mode: Some(mode).filter(|&m| m != Self::UNKNOWN),
+        }
+    }
+}
@@ -2,11 +2,24 @@ mod slice;
 mod backpack;
 mod file;
 mod in_memory;
+mod lazy;
 mod maybe_ref;
+mod entry;
+mod metadata;
+pub(crate) mod path;
+pub mod codec;
+mod crypto;
+#[cfg(feature = "async")]
+pub(crate) mod async_io;
 
 pub use file::RawFile;
 pub use in_memory::InMemoryFile;
-pub use crate::pack::backpack::BackPack;
+pub use lazy::LazyFile;
+pub use crate::pack::backpack::{BackPack, FlushMode, PackStreamReader, PackWriter, StreamEntry};
+pub use crate::pack::entry::{DirEntry, Entry};
+#[cfg(feature = "encryption")]
+pub use crate::pack::crypto::KeyProvider;
+pub use crate::pack::metadata::EntryMetadata;
 pub use crate::error::{PackError, Result};
 
 pub const fn parse_int(s: &'static [u8]) -> u16 {
@@ -35,9 +48,24 @@ pub const fn parse_int(s: &'static [u8]) -> u16 {
 }
 
 pub const PACK_MAGIC: &[u8] = b"BACKPACK";
-pub const PACK_VERSION: u16 = parse_int(env!("CARGO_PKG_VERSION_MAJOR").as_bytes());
+/// Version of the file format packs are written in. Older versions can still be opened,
+/// see [`BackPack::upgrade`].
+///
+/// * 0: the format of all releases before the format was versioned separately from the crate
+/// * 1: adds toc block chaining for appends, codecs, checksums and file attributes
+/// * 2: adds toc entries for directories (see [`DIRECTORY`]), which older readers take for files
+/// * 3: adds encrypted toc blocks and entries, see `BackPack::create_encrypted`
+/// * 4: checksums of toc blocks cover the offset of the next block
+pub const PACK_VERSION: u16 = 4;
 pub const TOC_SIZE: u16 = 4096;
+/// Offset of toc entries for files which were removed by an appending flush
+pub const TOMBSTONE: u64 = u64::MAX;
+/// Offset of toc entries for directories, which only exist to be listed
+pub const DIRECTORY: u64 = u64::MAX - 1;
 pub const PACK_HEADER_SIZE: u64 = 26;
+/// Packs storing more data than this are opened partially by [`BackPack::open`],
+/// keeping at most this many bytes of file contents in memory.
+pub const MAX_ALLOWED_IN_MEMORY: usize = 256 * 1024 * 1024;
 
 #[cfg(test)]
 mod tests {
@@ -46,11 +74,13 @@ mod tests {
     use crate::pack::PACK_VERSION;
     use crate::pack::backpack::BackPack;
     use crate::error::PackError;
+    use crate::pack::{FlushMode, PackStreamReader, PackWriter, PACK_HEADER_SIZE, PACK_MAGIC, TOC_SIZE};
+    use crate::pack::codec::{self, Codec};
+    #[cfg(feature = "deflate")]
+    use crate::pack::codec::DEFLATE;
+    use crate::pack::DirEntry;
+    use std::io::{Read, Seek, SeekFrom, Write};
 
-    #[test]
-    pub fn test_version() {
-        assert_eq!(PACK_VERSION, env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap())
-    }
 
     #[test]
     pub fn parse_int() {
@@ -93,4 +123,1299 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_many_files() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        for i in 0..1000 {
+            bp.add_file_named(format!("contents of {}", i), format!("dir/file_{}.txt", i))?;
+        }
+        let file = bp.close()?;
+
+        let bp = BackPack::open(file)?;
+        for i in 0..1000 {
+            let f = bp.get_file(format!("dir/file_{}.txt", i))?;
+            assert_eq!(&*f.get_bytes(), format!("contents of {}", i).as_bytes());
+        }
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_partial() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        for i in 0..500 {
+            bp.add_file_named(vec![i as u8; 100], format!("file_{}", i))?;
+        }
+        let file = bp.close()?;
+
+        let bp = BackPack::open_partial_with_limit(file, 1000)?;
+        assert_eq!(bp.memory_bytes(), 0);
+
+        for i in 0..500 {
+            let f = bp.get_file(format!("file_{}", i))?;
+            assert_eq!(&*f.get_bytes(), &[i as u8; 100]);
+            assert!(bp.memory_bytes() <= 1000);
+        }
+
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_file() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        let contents = (0..100000).map(|i| i as u8).collect::<Vec<_>>();
+        bp.add_file_named(contents.clone(), "large")?;
+        bp.add_file_named("small", "small")?;
+        let file = bp.close()?;
+
+        let bp = BackPack::open_partial_with_limit(file, 1000)?;
+        let mut f = bp.get_file_lazy("large")?;
+        assert!(f.reads_backing_file());
+        assert_eq!(f.metadata().len(), 100000);
+
+        let mut head = [0; 4096];
+        f.read_exact(&mut head)?;
+        assert_eq!(&head[..], &contents[..4096]);
+        f.seek(SeekFrom::End(-10))?;
+        let mut tail = Vec::new();
+        f.read_to_end(&mut tail)?;
+        assert_eq!(tail, &contents[100000 - 10..]);
+        assert!(f.seek(SeekFrom::Current(-100001)).is_err());
+
+        let mut buf = [0; 10];
+        f.read_exact_at(&mut buf, 50000)?;
+        assert_eq!(&buf[..], &contents[50000..50010]);
+        assert!(f.read_exact_at(&mut buf, 99995).is_err());
+        assert_eq!(bp.memory_bytes(), 0);
+
+        // changed contents are only in memory
+        bp.get_file("small")?.write_all(b"changed")?;
+        let mut f = bp.get_file_lazy("small")?;
+        assert!(!f.reads_backing_file());
+        let mut changed = String::new();
+        f.read_to_string(&mut changed)?;
+        assert_eq!(changed, "changed");
+
+        let entry = bp.entries().into_iter().find(|e| e.name() == "large").unwrap();
+        assert!(entry.open_lazy().reads_backing_file());
+        let bp = BackPack::open_complete(bp.close()?)?;
+        assert!(!bp.get_file_lazy("large")?.reads_backing_file());
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_modifications() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        for i in 0..200 {
+            bp.add_file_named(vec![i as u8; 50], format!("file_{}", i))?;
+        }
+        let file = bp.close()?;
+
+        let mut bp = BackPack::open_partial_with_limit(file, 200)?;
+        for i in 0..100 {
+            bp.remove_file(format!("file_{}", i))?;
+        }
+        assert!(matches!(bp.get_file("file_0"), Err(PackError::FileNotFound(_))));
+
+        // add enough files with long names that the table of contents grows
+        for i in 0..200 {
+            bp.add_file_named(vec![i as u8; 10], format!("{:0>100}", i))?;
+        }
+        bp.flush()?;
+
+        for i in 100..200 {
+            assert_eq!(&*bp.get_file(format!("file_{}", i))?.get_bytes(), &[i as u8; 50]);
+        }
+        let file = bp.close()?;
+
+        let bp = BackPack::open_complete(file)?;
+        assert!(matches!(bp.get_file("file_0"), Err(PackError::FileNotFound(_))));
+        for i in 100..200 {
+            assert_eq!(&*bp.get_file(format!("file_{}", i))?.get_bytes(), &[i as u8; 50]);
+        }
+        for i in 0..200 {
+            assert_eq!(&*bp.get_file(format!("{:0>100}", i))?.get_bytes(), &[i as u8; 10]);
+        }
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_flush() -> Result<(), PackError> {
+        let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named(vec![1; 10000], "large")?;
+        bp.add_file_named("small", "small")?;
+        bp.add_file_named("removed", "removed")?;
+        bp.flush()?;
+
+        let mut file = bp.close()?;
+        let size_before = file.seek(SeekFrom::End(0))?;
+
+        let mut bp = BackPack::open(file)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("new", "new")?;
+        bp.add_file_named("replaced", "small")?;
+        bp.remove_file("removed")?;
+
+        // only the new data and a single toc block are written
+        let mut file = bp.close()?;
+        let size_after = file.seek(SeekFrom::End(0))?;
+        assert_eq!(size_after - size_before, 3 + 8 + TOC_SIZE as u64);
+
+        let mut bp = BackPack::open(file)?;
+        assert_eq!(&*bp.get_file("large")?.get_bytes(), &[1; 10000]);
+        assert_eq!(&*bp.get_file("small")?.get_bytes(), b"replaced");
+        assert_eq!(&*bp.get_file("new")?.get_bytes(), b"new");
+        assert!(matches!(bp.get_file("removed"), Err(PackError::FileNotFound(_))));
+
+        // rewriting reclaims the space of removed and replaced files
+        bp.set_flush_mode(FlushMode::Rewrite);
+        bp.flush()?;
+        let mut file = bp.close()?;
+        assert!(file.seek(SeekFrom::End(0))? < size_before);
+
+        let bp = BackPack::open_partial_with_limit(file, 100)?;
+        assert_eq!(&*bp.get_file("small")?.get_bytes(), b"replaced");
+        assert!(matches!(bp.get_file("removed"), Err(PackError::FileNotFound(_))));
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_flush_partial() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        for i in 0..100 {
+            bp.add_file_named(vec![i as u8; 100], format!("file_{}", i))?;
+        }
+        let file = bp.close()?;
+
+        let mut bp = BackPack::open_partial_with_limit(file, 500)?;
+        bp.set_flush_mode(FlushMode::Append);
+        for round in 0..3 {
+            for i in 0..100 {
+                bp.add_file_named(vec![i as u8; 10], format!("round_{}/{:0>100}", round, i))?;
+            }
+            bp.remove_file(format!("file_{}", round))?;
+            bp.flush()?;
+        }
+        let file = bp.close()?;
+
+        let bp = BackPack::open_partial_with_limit(file, 500)?;
+        for i in 0..100 {
+            let f = bp.get_file(format!("file_{}", i));
+            if i < 3 {
+                assert!(matches!(f, Err(PackError::FileNotFound(_))));
+            } else {
+                assert_eq!(&*f?.get_bytes(), &[i as u8; 100]);
+            }
+
+            for round in 0..3 {
+                let f = bp.get_file(format!("round_{}/{:0>100}", round, i))?;
+                assert_eq!(&*f.get_bytes(), &[i as u8; 10]);
+            }
+        }
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "deflate")]
+    fn test_compression() -> Result<(), PackError> {
+        let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.set_default_codec(DEFLATE)?;
+        bp.add_file_named(vec![1; 100000], "compressible")?;
+        let random = (0..1000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect::<Vec<_>>();
+        bp.add_file_named(random.clone(), "random")?;
+        bp.add_file_with_codec(InMemoryFile::new("empty"), DEFLATE)?;
+
+        let mut file = bp.close()?;
+        assert!(file.seek(SeekFrom::End(0))? < 100000);
+
+        let mut bp = BackPack::open(file)?;
+        assert_eq!(&*bp.get_file("compressible")?.get_bytes(), &[1; 100000]);
+        assert_eq!(&*bp.get_file("random")?.get_bytes(), &random);
+        assert!(bp.get_file("empty")?.get_bytes().is_empty());
+
+        // unchanged entries stay compressed when rewriting a partially parsed pack
+        bp.set_flush_mode(FlushMode::Rewrite);
+        let file = bp.close()?;
+        let mut bp = BackPack::open_partial_with_limit(file, 1000)?;
+        bp.add_file_with_codec(RawFile::from(vec![2; 100000]).with_name("added"), DEFLATE)?;
+        bp.remove_file("random")?;
+        bp.flush()?;
+        assert_eq!(&*bp.get_file("compressible")?.get_bytes(), &[1; 100000]);
+
+        let mut file = bp.close()?;
+        assert!(file.seek(SeekFrom::End(0))? < 100000);
+
+        let bp = BackPack::open_partial_with_limit(file, 1000)?;
+        assert_eq!(&*bp.get_file("added")?.get_bytes(), &[2; 100000]);
+        assert!(matches!(bp.get_file("random"), Err(PackError::FileNotFound(_))));
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_codec() -> Result<(), PackError> {
+        struct Halve;
+
+        // stores every other byte of files that consist of pairs
+        impl Codec for Halve {
+            fn id(&self) -> u16 {
+                1000
+            }
+
+            fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+                Ok(data.iter().step_by(2).copied().collect())
+            }
+
+            fn decompress(&self, data: &[u8], _size: u64) -> std::io::Result<Vec<u8>> {
+                Ok(data.iter().flat_map(|&i| [i, i]).collect())
+            }
+        }
+
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        assert!(matches!(bp.add_file_with_codec(InMemoryFile::new("a"), 1000), Err(PackError::UnknownCodec(1000))));
+
+        codec::register_codec(Halve);
+        bp.add_file_with_codec(RawFile::from(vec![1, 1, 2, 2, 3, 3]).with_name("a"), 1000)?;
+        let file = bp.close()?;
+
+        let bp = BackPack::open(file)?;
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), &[1, 1, 2, 2, 3, 3]);
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named(vec![1; 100], "a")?;
+        bp.add_file_named(vec![2; 100], "b")?;
+        bp.add_file_named(vec![3; 100], "c")?;
+        let mut file = bp.close()?;
+
+        // flip a byte in the first and the last entry
+        file.seek(SeekFrom::Start(PACK_HEADER_SIZE + TOC_SIZE as u64 + 50))?;
+        file.write_all(&[0])?;
+        file.seek(SeekFrom::End(-1))?;
+        file.write_all(&[0])?;
+
+        let bp = BackPack::open_partial(file)?;
+        match bp.verify() {
+            Err(PackError::Corrupted(names)) => assert_eq!(names, vec!["a", "c"]),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(bp.get_file("a")?.try_get_bytes(), Err(PackError::Corrupted(_))));
+        assert_eq!(&*bp.get_file("b")?.get_bytes(), &[2; 100]);
+        let file = bp.close()?;
+        assert!(matches!(BackPack::open_complete(file), Err(PackError::Corrupted(_))));
+
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named(vec![1; 100], "a")?;
+        let mut file = bp.close()?;
+
+        // the table of contents is checked as well
+        file.seek(SeekFrom::Start(PACK_HEADER_SIZE + 20))?;
+        file.write_all(&[0xff])?;
+        assert!(matches!(BackPack::open(file), Err(PackError::CorruptedToc(PACK_HEADER_SIZE))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named(vec![1; 100], "a")?;
+        let mut file = bp.close()?;
+
+        let size = file.seek(SeekFrom::End(0))?;
+        file.set_len(size - 10)?;
+        assert!(matches!(BackPack::open(file), Err(PackError::Truncated(expected, actual)) if expected == size && actual == size - 10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named("a", "a.txt")?;
+        bp.add_file_named("bb", "dir/b.txt")?;
+        bp.add_file_named("ccc", "dir/sub/c.txt")?;
+        bp.add_file_named("dddd", "dir/sub/d.txt")?;
+        bp.add_file_named("removed", "dir/removed.txt")?;
+        bp.remove_file("dir/removed.txt")?;
+
+        let entries = bp.entries().into_iter()
+            .map(|e| (e.name().to_string(), e.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![
+            ("a.txt".to_string(), 1),
+            ("dir/b.txt".to_string(), 2),
+            ("dir/sub/c.txt".to_string(), 3),
+            ("dir/sub/d.txt".to_string(), 4),
+        ]);
+        assert_eq!(&*bp.iter().nth(1).unwrap().open().get_bytes(), b"bb");
+
+        let names = |entries: Vec<DirEntry>| entries.iter()
+            .map(|e| (e.name().to_string(), e.is_dir()))
+            .collect::<Vec<_>>();
+        assert_eq!(names(bp.read_dir("")?), vec![("a.txt".to_string(), false), ("dir".to_string(), true)]);
+        assert_eq!(names(bp.read_dir("dir/")?), vec![("dir/b.txt".to_string(), false), ("dir/sub".to_string(), true)]);
+        assert_eq!(bp.read_dir("dir/sub")?.len(), 2);
+        assert!(matches!(bp.read_dir("nonexistent"), Err(PackError::FileNotFound(_))));
+
+        // removed files can be added again
+        bp.add_file_named("new", "dir/removed.txt")?;
+        assert_eq!(bp.entries().len(), 5);
+        assert_eq!(&*bp.get_file("dir/removed.txt")?.get_bytes(), b"new");
+        let file = bp.close()?;
+
+        let bp = BackPack::open_partial(file)?;
+        assert_eq!(bp.entries().len(), 5);
+        let sizes = (&bp).into_iter().map(|e| e.size()).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![1, 2, 3, 3, 4]);
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata() -> Result<(), PackError> {
+        let path = std::env::temp_dir().join(format!("backpack_test_metadata_{}", std::process::id()));
+        std::fs::write(&path, "#!/bin/sh")?;
+        #[cfg(unix)]
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+        let on_disk = std::fs::metadata(&path)?;
+
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named(RawFile::open(&path)?, "script.sh")?;
+        bp.add_file_named("in memory", "memory.txt")?;
+        std::fs::remove_file(&path)?;
+
+        let metadata = bp.metadata("memory.txt")?;
+        assert_eq!(metadata.len(), 9);
+        assert!(metadata.modified().is_err());
+        assert_eq!(metadata.mode(), None);
+        assert_eq!(InMemoryFile::from("abc").metadata()?.len(), 3);
+        let file = bp.close()?;
+
+        let check = |bp: &BackPack| -> Result<(), PackError> {
+            let metadata = bp.get_file("script.sh")?.metadata()?;
+            assert_eq!(metadata.len(), 9);
+            assert_eq!(metadata.modified()?, on_disk.modified()?);
+            #[cfg(unix)]
+            assert_eq!(metadata.mode().map(|m| m & 0o777), Some(0o755));
+            Ok(())
+        };
+
+        let bp = BackPack::open_complete(file)?;
+        check(&bp)?;
+        assert!(bp.metadata("memory.txt")?.modified().is_err());
+        let file = bp.close()?;
+
+        let mut bp = BackPack::open_partial(file)?;
+        check(&bp)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.remove_file("memory.txt")?;
+        let file = bp.close()?;
+
+        let bp = BackPack::open(file)?;
+        check(&bp)?;
+        assert!(matches!(bp.metadata("memory.txt"), Err(PackError::FileNotFound(_))));
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deduplicate() -> Result<(), PackError> {
+        let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.set_deduplicate(true)?;
+        for i in 0..10 {
+            bp.add_file_named(vec![1; 1000], format!("{}/LICENSE", i))?;
+        }
+        bp.add_file_named(vec![2; 1000], "other")?;
+        assert_eq!(bp.memory_bytes(), 2000);
+
+        // each unique blob is only written once
+        let mut file = bp.close()?;
+        assert!(file.seek(SeekFrom::End(0))? < 3000 + TOC_SIZE as u64);
+
+        let mut bp = BackPack::open_partial(file)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.set_deduplicate(true)?;
+        bp.add_file_named(vec![2; 1000], "duplicate of other")?;
+        for i in 0..9 {
+            bp.remove_file(format!("{}/LICENSE", i))?;
+        }
+        let mut file = bp.close()?;
+        assert!(file.seek(SeekFrom::End(0))? < 3000 + 2 * TOC_SIZE as u64);
+
+        let mut bp = BackPack::open(file)?;
+        assert_eq!(&*bp.get_file("9/LICENSE")?.get_bytes(), &[1; 1000]);
+        assert_eq!(&*bp.get_file("duplicate of other")?.get_bytes(), &[2; 1000]);
+
+        // contents stay alive while any file refers to them
+        bp.set_flush_mode(FlushMode::Rewrite);
+        bp.remove_file("other")?;
+        let bp = BackPack::open_partial(bp.close()?)?;
+        assert_eq!(&*bp.get_file("duplicate of other")?.get_bytes(), &[2; 1000]);
+        assert_eq!(bp.entries().len(), 2);
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_mmap() -> Result<(), PackError> {
+        let path = std::env::temp_dir().join(format!("backpack_test_mmap_{}.bp", std::process::id()));
+
+        let bp = BackPack::create(RawFile::create(&path)?)?;
+        bp.add_file_named(vec![1; 1000], "stored")?;
+        #[cfg(feature = "deflate")]
+        bp.add_file_with_codec(RawFile::from(vec![2; 1000]).with_name("compressed"), DEFLATE)?;
+        bp.close()?;
+
+        let bp = BackPack::open_mmap(&path)?;
+        assert_eq!(&*bp.get_file("stored")?.get_bytes(), &[1; 1000]);
+        #[cfg(feature = "deflate")]
+        assert_eq!(&*bp.get_file("compressed")?.get_bytes(), &[2; 1000]);
+        // only compressed files are copied into memory
+        assert!(bp.memory_bytes() <= 1000);
+        bp.verify()?;
+
+        let mut f = bp.get_file_lazy("stored")?;
+        assert!(f.reads_backing_file());
+        let mut contents = Vec::new();
+        f.read_to_end(&mut contents)?;
+        assert_eq!(contents, &[1; 1000]);
+        #[cfg(feature = "deflate")]
+        assert!(!bp.get_file_lazy("compressed")?.reads_backing_file());
+
+        assert!(matches!(bp.add_file_named("new", "new"), Err(PackError::ReadOnly)));
+        assert!(matches!(bp.remove_file("stored"), Err(PackError::ReadOnly)));
+        assert!(bp.get_file("stored")?.write(b"changed").is_err());
+        bp.close()?;
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    /// Builds a pack like the releases before format version 1 wrote them
+    fn v0_pack(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut toc = vec![0; 10];
+        let mut data = Vec::new();
+        for (name, contents) in files {
+            toc.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            toc.extend_from_slice(name.as_bytes());
+            toc.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            toc.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            data.extend_from_slice(contents);
+        }
+        let filled = toc.len() as u16;
+        toc[..2].copy_from_slice(&filled.to_le_bytes());
+        toc.resize(TOC_SIZE as usize, 0);
+
+        let mut pack = PACK_MAGIC.to_vec();
+        pack.extend_from_slice(&0u16.to_le_bytes());
+        pack.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        pack.extend_from_slice(&PACK_HEADER_SIZE.to_le_bytes());
+        pack.extend(toc);
+        pack.extend(data);
+        pack
+    }
+
+    /// Builds a pack like format version 1 is written, with a single toc block
+    fn v1_pack(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut toc = vec![0; 14];
+        let mut data = Vec::new();
+        for (name, contents) in files {
+            toc.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            toc.extend_from_slice(name.as_bytes());
+            toc.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            toc.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            toc.extend_from_slice(&codec::STORE.to_le_bytes());
+            toc.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            toc.extend_from_slice(&crc32fast::hash(contents).to_le_bytes());
+            // unknown modification time and mode
+            toc.extend_from_slice(&0i64.to_le_bytes());
+            toc.extend_from_slice(&u32::MAX.to_le_bytes());
+            toc.extend_from_slice(&u32::MAX.to_le_bytes());
+            data.extend_from_slice(contents);
+        }
+        let filled = toc.len() as u16;
+        toc[..2].copy_from_slice(&filled.to_le_bytes());
+        toc.resize(TOC_SIZE as usize, 0);
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&toc[..2]);
+        hasher.update(&toc[14..]);
+        let checksum = hasher.finalize();
+        toc[10..14].copy_from_slice(&checksum.to_le_bytes());
+
+        let mut pack = PACK_MAGIC.to_vec();
+        pack.extend_from_slice(&1u16.to_le_bytes());
+        pack.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        pack.extend_from_slice(&PACK_HEADER_SIZE.to_le_bytes());
+        pack.extend(toc);
+        pack.extend(data);
+        pack
+    }
+
+    #[test]
+    fn test_open_v1() -> Result<(), PackError> {
+        let pack = v1_pack(&[("a", b"first"), ("dir/b", b"second")]);
+
+        let bp = BackPack::open(RawFile::from(pack.clone()))?;
+        assert_eq!(bp.version(), 1);
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"first");
+        assert_eq!(&*bp.get_file("dir/b")?.get_bytes(), b"second");
+        bp.verify()?;
+        bp.close()?;
+
+        let bp = BackPack::open_partial(RawFile::from(pack.clone()))?;
+        assert_eq!(bp.version(), 1);
+        assert_eq!(&*bp.get_file("dir/b")?.get_bytes(), b"second");
+        bp.add_file_named("third", "c")?;
+        let bp = BackPack::open(bp.close()?)?;
+        assert_eq!(bp.version(), PACK_VERSION);
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"first");
+        assert_eq!(&*bp.get_file("c")?.get_bytes(), b"third");
+        bp.close()?;
+
+        // changes can't be appended to older packs, so they're rewritten in the current format
+        let mut bp = BackPack::open(RawFile::from(pack))?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("fourth", "d")?;
+        let mut bp = BackPack::open(bp.close()?)?;
+        assert_eq!(bp.version(), PACK_VERSION);
+        bp.set_flush_mode(FlushMode::Append);
+        bp.create_dir("empty")?;
+        let bp = BackPack::open(bp.close()?)?;
+        assert!(bp.metadata("empty")?.is_dir());
+        assert_eq!(&*bp.get_file("d")?.get_bytes(), b"fourth");
+        bp.verify()?;
+        bp.close()?;
+
+        let mut pack = v1_pack(&[("a", b"a")]);
+        pack[PACK_MAGIC.len()..PACK_MAGIC.len() + 2].copy_from_slice(&(PACK_VERSION + 1).to_le_bytes());
+        assert!(matches!(BackPack::open(RawFile::from(pack)), Err(PackError::Incompatible(v)) if v == PACK_VERSION + 1));
+
+        Ok(())
+    }
+
+    /// Changes the link to the next block of the toc block at `offset`, with a valid checksum
+    fn relink(pack: &mut [u8], offset: u64, next: u64) {
+        let block = &mut pack[offset as usize..offset as usize + TOC_SIZE as usize];
+        block[2..10].copy_from_slice(&next.to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&block[..10]);
+        hasher.update(&block[14..]);
+        let checksum = hasher.finalize();
+        block[10..14].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    #[test]
+    fn test_toc_cycle() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named("first", "a")?;
+        let mut bp = BackPack::open(bp.close()?)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("second", "b")?;
+        let mut file = bp.close()?;
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+        let appended = (raw.len() - TOC_SIZE as usize) as u64;
+
+        // chains of toc blocks which loop back are corrupted, instead of being read forever
+        let mut looped = raw.clone();
+        relink(&mut looped, PACK_HEADER_SIZE, PACK_HEADER_SIZE);
+        assert!(matches!(BackPack::open(RawFile::from(looped)), Err(PackError::CorruptedToc(PACK_HEADER_SIZE))));
+        let mut looped = raw.clone();
+        relink(&mut looped, appended, PACK_HEADER_SIZE);
+        assert!(matches!(BackPack::open(RawFile::from(looped)), Err(PackError::CorruptedToc(PACK_HEADER_SIZE))));
+
+        // verify walks the chain on disk as well
+        let path = std::env::temp_dir().join(format!("backpack_test_toc_cycle_{}.bp", std::process::id()));
+        std::fs::write(&path, &raw)?;
+        let bp = BackPack::open_partial(RawFile::open(&path)?)?;
+        bp.verify()?;
+        relink(&mut raw, appended, PACK_HEADER_SIZE);
+        std::fs::write(&path, &raw)?;
+        assert!(matches!(bp.verify(), Err(PackError::CorruptedToc(PACK_HEADER_SIZE))));
+        bp.close_drop_unwritten_changes()?;
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_link() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named("first", "a")?;
+        let mut bp = BackPack::open(bp.close()?)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("second", "b")?;
+        let mut file = bp.close()?;
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+
+        // the link to the appended toc block is covered by the checksum of the block before it
+        let bp = BackPack::open(RawFile::from(raw.clone()))?;
+        assert_eq!(&*bp.get_file("b")?.get_bytes(), b"second");
+        bp.close()?;
+
+        let next = PACK_HEADER_SIZE as usize + 2;
+        raw[next..next + 8].copy_from_slice(&0u64.to_le_bytes());
+        assert!(matches!(BackPack::open(RawFile::from(raw)), Err(PackError::CorruptedToc(PACK_HEADER_SIZE))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_v0() -> Result<(), PackError> {
+        let pack = v0_pack(&[("a", b"first"), ("dir/b", b"second")]);
+
+        let bp = BackPack::open_partial(RawFile::from(pack.clone()))?;
+        assert_eq!(bp.version(), 0);
+        assert_eq!(&*bp.get_file("dir/b")?.get_bytes(), b"second");
+        bp.verify()?;
+        bp.close()?;
+
+        // changes to old packs rewrite them in the current format
+        let bp = BackPack::open_complete(RawFile::from(pack.clone()))?;
+        assert_eq!(bp.version(), 0);
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"first");
+        bp.add_file_named("third", "c")?;
+        let mut file = bp.close()?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let bp = BackPack::open(file)?;
+        assert_eq!(bp.version(), PACK_VERSION);
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"first");
+        assert_eq!(&*bp.get_file("c")?.get_bytes(), b"third");
+        bp.close()?;
+
+        let mut file = BackPack::upgrade(RawFile::from(pack))?;
+        file.seek(SeekFrom::Start(0))?;
+        let bp = BackPack::open(file)?;
+        assert_eq!(bp.version(), PACK_VERSION);
+        assert_eq!(&*bp.get_file("dir/b")?.get_bytes(), b"second");
+        bp.verify()?;
+        bp.close()?;
+
+        assert!(matches!(
+            BackPack::open(RawFile::from(v0_pack(&[("a", b"a")])[..PACK_HEADER_SIZE as usize + 100].to_vec())),
+            Err(PackError::CorruptedToc(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_toc_entries() -> Result<(), PackError> {
+        // names are normalized, names escaping the root are rejected
+        let bp = BackPack::open(RawFile::from(v1_pack(&[("dir/../a", b"first"), ("./b", b"second")])))?;
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"first");
+        assert_eq!(bp.read_dir("")?.len(), 2);
+        bp.close()?;
+        assert!(matches!(BackPack::open(RawFile::from(v1_pack(&[("../a", b"first")]))), Err(PackError::InvalidName(_))));
+        assert!(matches!(BackPack::open(RawFile::from(v0_pack(&[("a/../..", b"first")]))), Err(PackError::InvalidName(_))));
+
+        // entries which don't fit in the filled part of the block
+        let mut pack = v1_pack(&[("a", b"first")]);
+        let block = &mut pack[PACK_HEADER_SIZE as usize..PACK_HEADER_SIZE as usize + TOC_SIZE as usize];
+        block[..2].copy_from_slice(&20u16.to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&block[..2]);
+        hasher.update(&block[14..]);
+        let checksum = hasher.finalize();
+        block[10..14].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(BackPack::open(RawFile::from(pack)), Err(PackError::CorruptedToc(PACK_HEADER_SIZE))));
+
+        let mut pack = v0_pack(&[("a", b"first")]);
+        pack[PACK_HEADER_SIZE as usize..PACK_HEADER_SIZE as usize + 2].copy_from_slice(&20u16.to_le_bytes());
+        assert!(matches!(BackPack::open(RawFile::from(pack)), Err(PackError::CorruptedToc(PACK_HEADER_SIZE))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_bytes() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named(vec![1; 1000], "a")?;
+        bp.add_file_named(vec![2; 500], "b")?;
+        bp.copy("b", "c")?;
+        assert_eq!(bp.memory_bytes(), 1500);
+
+        // contents are dropped with the last file referring to them
+        bp.remove_file("b")?;
+        assert_eq!(bp.memory_bytes(), 1500);
+        bp.remove_file("c")?;
+        assert_eq!(bp.memory_bytes(), 1000);
+        bp.add_file_named(vec![3; 10], "a")?;
+        assert_eq!(bp.memory_bytes(), 10);
+
+        // unless a handle to them is still open
+        let mut f = bp.get_file("a")?;
+        bp.remove_file("a")?;
+        assert_eq!(bp.memory_bytes(), 0);
+        f.write_all(b"still open")?;
+        assert_eq!(&*f.get_bytes(), b"still open");
+        drop(f);
+        assert!(matches!(bp.get_file("a"), Err(PackError::FileNotFound(_))));
+        bp.close()?;
+
+        // opened packs hold the decompressed contents
+        #[cfg(feature = "deflate")]
+        {
+            let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+            bp.set_default_codec(DEFLATE)?;
+            bp.add_file_named(vec![1; 100000], "compressed")?;
+            let bp = BackPack::open(bp.close()?)?;
+            assert!(bp.data_size() < 1000);
+            assert_eq!(bp.memory_bytes(), 100000);
+            bp.close()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_packed() -> Result<(), PackError> {
+        let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.set_deduplicate(true)?;
+        bp.add_file_named("hello", "a")?;
+        bp.add_file_named("hello", "b")?;
+
+        let mut f = bp.get_file("a")?;
+        f.seek(SeekFrom::End(0))?;
+        f.write_all(b", world")?;
+        assert_eq!(&*f.get_bytes(), b"hello, world");
+        assert_eq!(bp.metadata("a")?.len(), 12);
+        // the contents of b were shared with a, and are copied before writing
+        assert_eq!(&*bp.get_file("b")?.get_bytes(), b"hello");
+        assert_eq!(bp.memory_bytes(), 5 + 12);
+
+        f.set_len(4)?;
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"hell");
+        drop(f);
+
+        bp.add_empty_file("c")?.write_all(b"new")?;
+        let mut file = bp.close()?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut bp = BackPack::open(file)?;
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"hell");
+        assert_eq!(&*bp.get_file("b")?.get_bytes(), b"hello");
+        assert_eq!(&*bp.get_file("c")?.get_bytes(), b"new");
+
+        // flushed files are written again by appending flushes
+        bp.set_flush_mode(FlushMode::Append);
+        bp.get_file("b")?.write_all(b"j")?;
+        bp.flush()?;
+        bp.get_file("c")?.write_all(b"N")?;
+        let mut file = bp.close()?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let bp = BackPack::open_partial(file)?;
+        bp.verify()?;
+        assert_eq!(&*bp.get_file("b")?.get_bytes(), b"jello");
+        assert_eq!(&*bp.get_file("c")?.get_bytes(), b"New");
+        bp.get_file("a")?.write_all(b"y")?;
+        let mut file = bp.close()?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let bp = BackPack::open_complete(file)?;
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"yell");
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_copy() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named("hello", "a")?;
+        bp.add_file_named("world", "b")?;
+
+        assert!(matches!(bp.rename("missing", "c"), Err(PackError::FileNotFound(_))));
+        assert!(matches!(bp.copy("a", "b"), Err(PackError::FileExists(_))));
+
+        bp.copy("a", "c")?;
+        bp.rename("a", "d")?;
+        let names = bp.entries().iter().map(|e| e.name().to_string()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["b", "c", "d"]);
+        assert_eq!(bp.memory_bytes(), 10);
+
+        bp.get_file("c")?.write_all(b"j")?;
+        assert_eq!(&*bp.get_file("d")?.get_bytes(), b"hello");
+        let mut file = bp.close()?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let bp = BackPack::open_partial(file)?;
+        bp.remove_file("b")?;
+        bp.copy("c", "b")?;
+        bp.rename("d", "a")?;
+        let mut file = bp.close()?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let bp = BackPack::open(file)?;
+        assert_eq!(&*bp.get_file("a")?.get_bytes(), b"hello");
+        assert_eq!(&*bp.get_file("b")?.get_bytes(), b"jello");
+        assert_eq!(&*bp.get_file("c")?.get_bytes(), b"jello");
+
+        // only one of the files copied to the same name at the same time is copied
+        for i in 0..8 {
+            bp.add_file_named(format!("{}", i), format!("source{}", i))?;
+        }
+        let copied = std::thread::scope(|s| {
+            let bp = &bp;
+            let threads = (0..8)
+                .map(|i| s.spawn(move || bp.copy(format!("source{}", i), "target").is_ok()))
+                .collect::<Vec<_>>();
+            threads.into_iter().map(|t| t.join().unwrap()).filter(|&copied| copied).count()
+        });
+        assert_eq!(copied, 1);
+        bp.close()?;
+
+        Ok(())
+    }
+
+    fn dir_names<'f>(bp: &'f BackPack<'f, '_>, dir: &str) -> Result<Vec<String>, PackError> {
+        Ok(bp.read_dir(dir)?.iter().map(|e| e.name().to_string()).collect())
+    }
+
+    #[test]
+    fn test_directories() -> Result<(), PackError> {
+        let bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named("hello", "./assets//a.txt")?;
+        bp.add_file_named("world", "assets\\b.txt")?;
+        assert_eq!(&*bp.get_file("assets/x/../a.txt")?.get_bytes(), b"hello");
+        assert_eq!(&*bp.get_file("assets/b.txt")?.get_bytes(), b"world");
+
+        for invalid in ["../a.txt", "assets/../../a.txt", "/a.txt", "\\a.txt", "C:\\a.txt", "."] {
+            assert!(matches!(bp.add_file_named("", invalid), Err(PackError::InvalidName(_))), "{}", invalid);
+        }
+        assert!(matches!(bp.add_file_named("", "assets"), Err(PackError::IsADirectory(_))));
+        assert!(matches!(bp.add_file_named("", "assets/a.txt/c"), Err(PackError::NotADirectory(_))));
+
+        bp.create_dir("empty")?;
+        bp.create_dir_all("deep/er/dir")?;
+        assert!(matches!(bp.create_dir("empty"), Err(PackError::FileExists(_))));
+        assert!(matches!(bp.create_dir("missing/dir"), Err(PackError::FileNotFound(_))));
+        assert!(matches!(bp.create_dir_all("assets/a.txt/dir"), Err(PackError::NotADirectory(_))));
+        assert!(bp.metadata("deep/er")?.is_dir());
+        assert!(bp.metadata("assets")?.is_dir());
+        assert!(bp.metadata("assets/a.txt")?.is_file());
+
+        assert_eq!(dir_names(&bp, "")?, vec!["assets", "deep", "empty"]);
+        assert!(dir_names(&bp, "empty")?.is_empty());
+        assert!(matches!(bp.read_dir("assets/a.txt"), Err(PackError::NotADirectory(_))));
+
+        assert!(matches!(bp.remove_dir("deep/er"), Err(PackError::DirectoryNotEmpty(_))));
+        bp.remove_dir("deep/er/dir")?;
+        bp.remove_dir_all("assets")?;
+        assert!(matches!(bp.get_file("assets/a.txt"), Err(PackError::FileNotFound(_))));
+        assert_eq!(dir_names(&bp, "")?, vec!["deep", "empty"]);
+        let mut file = bp.close()?;
+
+        // directories are kept by rewriting and appending flushes
+        file.seek(SeekFrom::Start(0))?;
+        let mut bp = BackPack::open_partial(file)?;
+        assert_eq!(dir_names(&bp, "")?, vec!["deep", "empty"]);
+        assert!(dir_names(&bp, "deep/er")?.is_empty());
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("file", "empty/file")?;
+        bp.remove_dir_all("deep")?;
+        bp.create_dir("new")?;
+        let mut file = bp.close()?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let bp = BackPack::open(file)?;
+        assert_eq!(dir_names(&bp, "")?, vec!["empty", "new"]);
+        assert_eq!(dir_names(&bp, "empty")?, vec!["empty/file"]);
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_writer() -> Result<(), PackError> {
+        let mut writer = PackWriter::create(RawFile::in_memory("test.bp"))?;
+        writer.add_file_named(&[1; 10000][..], "large")?;
+        writer.add_file_named("".as_bytes(), "empty")?;
+        writer.add_file(RawFile::in_memory("dir/named.txt"))?;
+        writer.create_dir_all("empty_dir/nested")?;
+
+        assert!(matches!(writer.add_file_named("again".as_bytes(), "large"), Err(PackError::FileExists(_))));
+        assert!(matches!(writer.add_file_named("a".as_bytes(), "dir"), Err(PackError::IsADirectory(_))));
+        assert!(matches!(writer.add_file_named("a".as_bytes(), "large/a"), Err(PackError::NotADirectory(_))));
+        let file = writer.finish()?;
+
+        let bp = BackPack::open(file)?;
+        assert_eq!(&*bp.get_file("large")?.get_bytes(), &[1; 10000]);
+        assert!(bp.get_file("empty")?.get_bytes().is_empty());
+        assert!(bp.get_file("dir/named.txt")?.get_bytes().is_empty());
+        assert!(bp.metadata("empty_dir/nested")?.is_dir());
+        bp.verify()?;
+
+        // packs written by a writer can be appended to
+        let mut bp = BackPack::open(bp.close()?)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("appended", "appended")?;
+        let bp = BackPack::open_partial_with_limit(bp.close()?, 100)?;
+        assert_eq!(&*bp.get_file("appended")?.get_bytes(), b"appended");
+        assert_eq!(&*bp.get_file("large")?.get_bytes(), &[1; 10000]);
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "deflate")]
+    fn test_pack_writer_compression() -> Result<(), PackError> {
+        let mut writer = PackWriter::create(RawFile::in_memory("test.bp"))?;
+        writer.set_default_codec(DEFLATE)?;
+        writer.add_file_named(std::io::repeat(1).take(100000), "compressible")?;
+        let mut file = writer.finish()?;
+        assert!(file.seek(SeekFrom::End(0))? < 10000);
+
+        let bp = BackPack::open(file)?;
+        assert_eq!(&*bp.get_file("compressible")?.get_bytes(), &[1; 100000][..]);
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested() -> Result<(), PackError> {
+        let mut bp = BackPack::create(RawFile::in_memory("outer.bp"))?;
+        bp.set_deduplicate(true)?;
+        for locale in ["en", "nl"] {
+            let nested = bp.create_nested(format!("{}.bp", locale))?;
+            nested.add_file_named("hello", "greeting.txt")?;
+            nested.close()?;
+        }
+        let bp = BackPack::open(bp.close()?)?;
+
+        // changes to a nested pack are written to the outer pack when they're flushed
+        let mut nested = bp.open_nested("nl.bp")?;
+        nested.add_file_named("hallo", "greeting.txt")?;
+        nested.flush()?;
+        nested.remove_file("greeting.txt")?;
+        nested.close_drop_unwritten_changes()?;
+        let file = bp.close()?;
+
+        // nested packs can be opened partially, or from a clone of their file
+        let mut bp = BackPack::open_partial_with_limit(file, 100)?;
+        bp.set_deduplicate(true)?;
+        let nested = BackPack::open_partial(bp.get_file("nl.bp")?.try_clone()?)?;
+        assert_eq!(&*nested.get_file("greeting.txt")?.get_bytes(), b"hallo");
+        nested.close()?;
+        let nested = bp.open_nested("en.bp")?;
+        assert_eq!(&*nested.get_file("greeting.txt")?.get_bytes(), b"hello");
+        nested.close()?;
+
+        bp.set_flush_mode(FlushMode::Append);
+        let mut nested = bp.open_nested("en.bp")?;
+        nested.set_flush_mode(FlushMode::Append);
+        nested.add_file_named("hi", "short.txt")?;
+        nested.close()?;
+
+        let bp = BackPack::open(bp.close()?)?;
+        let nested = bp.open_nested("en.bp")?;
+        assert_eq!(&*nested.get_file("short.txt")?.get_bytes(), b"hi");
+        assert_eq!(&*nested.get_file("greeting.txt")?.get_bytes(), b"hello");
+        nested.close()?;
+        let nested = bp.open_nested("nl.bp")?;
+        assert!(nested.get_file("short.txt").is_err());
+        nested.close()?;
+        bp.close()?;
+
+        Ok(())
+    }
+
+    fn read_stream(pack: &[u8]) -> Result<Vec<(String, Vec<u8>)>, PackError> {
+        let mut reader = PackStreamReader::new(pack)?;
+        let mut entries = Vec::new();
+        while let Some(mut entry) = reader.next_entry()? {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            entries.push((entry.name().to_string(), contents));
+        }
+
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_stream_reader() -> Result<(), PackError> {
+        let pack_bytes = |mut file: RawFile| -> Result<Vec<u8>, PackError> {
+            let mut pack = Vec::new();
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut pack)?;
+            Ok(pack)
+        };
+
+        // rewritten packs store their toc first
+        let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.set_deduplicate(true)?;
+        bp.add_file_named(vec![1; 1000], "a")?;
+        bp.add_file_named(vec![2; 1000], "b")?;
+        bp.add_file_named(vec![1; 1000], "c")?;
+        bp.create_dir("dir")?;
+        let pack = pack_bytes(bp.close()?)?;
+        assert_eq!(read_stream(&pack)?, vec![
+            ("dir".to_string(), vec![]),
+            ("a".to_string(), vec![1; 1000]),
+            ("c".to_string(), vec![1; 1000]),
+            ("b".to_string(), vec![2; 1000]),
+        ]);
+
+        // contents which aren't read are skipped
+        let mut reader = PackStreamReader::new(pack.as_slice())?;
+        let mut names = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            names.push(entry.name().to_string());
+        }
+        assert_eq!(names, ["dir", "a", "c", "b"]);
+
+        // packs written by a writer have a header before every entry
+        let mut writer = PackWriter::create(RawFile::in_memory("test.bp"))?;
+        writer.add_file_named(&[1; 10000][..], "large")?;
+        writer.create_dir_all("empty_dir")?;
+        writer.add_file_named("".as_bytes(), "empty")?;
+        let file = writer.finish()?;
+        let pack = pack_bytes(file)?;
+        assert_eq!(read_stream(&pack)?, vec![
+            ("large".to_string(), vec![1; 10000]),
+            ("empty_dir".to_string(), vec![]),
+            ("empty".to_string(), vec![]),
+        ]);
+
+        let mut corrupted = pack.clone();
+        corrupted[5000] ^= 1;
+        // errors reading the contents of entries are returned as io errors
+        assert!(matches!(read_stream(&corrupted), Err(PackError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData));
+        assert!(matches!(read_stream(&pack[..5000]), Err(PackError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData));
+        assert!(matches!(read_stream(&pack[..50]), Err(PackError::Truncated(_, 50))));
+
+        // changes appended to a pack aren't stored where a stream reader expects them
+        let mut file = RawFile::in_memory("test.bp");
+        file.write_all(&pack)?;
+        let mut bp = BackPack::open(file)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.remove_file("empty")?;
+        let pack = pack_bytes(bp.close()?)?;
+        assert!(matches!(read_stream(&pack), Err(PackError::NotStreamable)));
+
+        let mut bp = BackPack::create(RawFile::in_memory("test.bp"))?;
+        bp.add_file_named("a", "a")?;
+        bp.flush()?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("b", "b")?;
+        let pack = pack_bytes(bp.close()?)?;
+        assert!(matches!(PackStreamReader::new(pack.as_slice()), Err(PackError::NotStreamable)));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn test_stream_reader_compressed() -> Result<(), PackError> {
+        let contents = (0..1 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut writer = PackWriter::create(RawFile::in_memory("test.bp"))?;
+        writer.set_default_codec(DEFLATE)?;
+        writer.add_file_named(contents.as_slice(), "large")?;
+        writer.add_file_named("after".as_bytes(), "after")?;
+        let mut file = writer.finish()?;
+        let mut pack = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut pack)?;
+        assert!(pack.len() < contents.len() / 10);
+
+        // compressed contents are decompressed while they're read
+        let mut reader = PackStreamReader::new(pack.as_slice())?;
+        let mut entry = reader.next_entry()?.unwrap();
+        let mut start = [0; 1000];
+        entry.read_exact(&mut start)?;
+        assert_eq!(start, contents[..1000]);
+        let mut rest = Vec::new();
+        entry.read_to_end(&mut rest)?;
+        assert_eq!(rest, contents[1000..]);
+        let mut entry = reader.next_entry()?.unwrap();
+        let mut after = String::new();
+        entry.read_to_string(&mut after)?;
+        assert_eq!(after, "after");
+        assert!(reader.next_entry()?.is_none());
+
+        let mut corrupted = pack.clone();
+        corrupted[PACK_HEADER_SIZE as usize + 200] ^= 1;
+        assert!(matches!(read_stream(&corrupted), Err(PackError::Io(_))));
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "encryption", feature = "deflate"))]
+    #[test]
+    fn test_encryption() -> Result<(), PackError> {
+        let key = [7; 32];
+        let mut bp = BackPack::create_encrypted(RawFile::in_memory("test.bp"), key)?;
+        bp.set_default_codec(DEFLATE)?;
+        bp.add_file_named("top secret contents", "secret_name.txt")?;
+        bp.add_file_named(vec![1; 1000], "large")?;
+        let mut file = bp.close()?;
+
+        // neither names nor contents can be found in the pack
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+        assert!(!raw.windows(11).any(|w| w == b"secret_name"));
+        assert!(!raw.windows(10).any(|w| w == b"top secret"));
+
+        assert!(matches!(BackPack::open(RawFile::from(raw.clone())), Err(PackError::KeyRequired)));
+        assert!(matches!(BackPack::open_encrypted(RawFile::from(raw.clone()), [8; 32]), Err(PackError::WrongKey)));
+        assert_eq!(BackPack::open_encrypted(RawFile::from(raw.clone()), key)?.version(), PACK_VERSION);
+
+        // appended toc blocks are encrypted as well
+        let mut bp = BackPack::open_encrypted(RawFile::from(raw.clone()), key)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("appended", "appended")?;
+        bp.remove_file("large")?;
+        let file = bp.close()?;
+
+        let bp = BackPack::open_partial_encrypted(file, key, 10)?;
+        assert_eq!(&*bp.get_file("secret_name.txt")?.get_bytes(), b"top secret contents");
+        assert_eq!(&*bp.get_file("appended")?.get_bytes(), b"appended");
+        assert!(matches!(bp.get_file("large"), Err(PackError::FileNotFound(_))));
+        bp.verify()?;
+        let file = bp.close()?;
+        BackPack::open_encrypted(file, key)?.close()?;
+
+        // changing the contents of a file or the table of contents is noticed
+        let mut tampered = raw.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let bp = BackPack::open_partial_encrypted(RawFile::from(tampered), key, 10)?;
+        assert!(matches!(bp.verify(), Err(PackError::Tampered(names)) if names == vec!["large"]));
+        bp.close()?;
+
+        let mut tampered = raw.clone();
+        tampered[PACK_HEADER_SIZE as usize + 100] ^= 1;
+        assert!(matches!(BackPack::open_encrypted(RawFile::from(tampered), key), Err(PackError::TamperedToc(PACK_HEADER_SIZE))));
+
+        // unencrypted packs can't be opened as if they were encrypted
+        let file = BackPack::create(RawFile::in_memory("test.bp"))?;
+        file.add_file_named("plain", "plain")?;
+        let file = file.close()?;
+        assert!(matches!(BackPack::open_encrypted(file, key), Err(PackError::NotEncrypted)));
+
+        // files are encrypted in chunks while they're streamed into the pack
+        let large = (0..300_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut writer = PackWriter::create_encrypted(RawFile::in_memory("test.bp"), key)?;
+        writer.add_file_named("streamed secret".as_bytes(), "streamed")?;
+        writer.set_default_codec(DEFLATE)?;
+        writer.add_file_named(large.as_slice(), "large")?;
+        let bp = BackPack::open_encrypted(writer.finish()?, key)?;
+        assert_eq!(&*bp.get_file("streamed")?.get_bytes(), b"streamed secret");
+        assert_eq!(&*bp.get_file("large")?.get_bytes(), large.as_slice());
+        bp.verify()?;
+        bp.close()?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption_truncated_toc() -> Result<(), PackError> {
+        let key = [7; 32];
+        let bp = BackPack::create_encrypted(RawFile::in_memory("test.bp"), key)?;
+        bp.add_file_named("first", "a")?;
+        let mut file = bp.close()?;
+        let mut original = Vec::new();
+        file.read_to_end(&mut original)?;
+
+        let mut bp = BackPack::open_encrypted(RawFile::from(original.clone()), key)?;
+        bp.set_flush_mode(FlushMode::Append);
+        bp.add_file_named("second", "b")?;
+        let mut file = bp.close()?;
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+
+        // unlinking the appended toc block is noticed, even though the checksum can be fixed,
+        // also when the rest of the pack is rolled back to before the append
+        let mut tampered = raw.clone();
+        relink(&mut tampered, PACK_HEADER_SIZE, 0);
+        assert!(matches!(BackPack::open_encrypted(RawFile::from(tampered.clone()), key), Err(PackError::TamperedToc(PACK_HEADER_SIZE))));
+        tampered.truncate(original.len());
+        tampered[..PACK_HEADER_SIZE as usize].copy_from_slice(&original[..PACK_HEADER_SIZE as usize]);
+        assert!(matches!(BackPack::open_encrypted(RawFile::from(tampered), key), Err(PackError::TamperedToc(PACK_HEADER_SIZE))));
+
+        // as is unlinking all toc blocks, or shrinking the data so appended data is cut off
+        let mut tampered = raw.clone();
+        tampered[18..PACK_HEADER_SIZE as usize].copy_from_slice(&0u64.to_le_bytes());
+        assert!(matches!(BackPack::open_encrypted(RawFile::from(tampered), key), Err(PackError::TamperedHeader)));
+        let mut tampered = raw.clone();
+        tampered[10..18].copy_from_slice(&original[10..18]);
+        assert!(matches!(BackPack::open_encrypted(RawFile::from(tampered), key), Err(PackError::TamperedHeader)));
+
+        let bp = BackPack::open_encrypted(RawFile::from(raw), key)?;
+        assert_eq!(&*bp.get_file("b")?.get_bytes(), b"second");
+        bp.close()?;
+
+        // empty packs have a toc block as well, so the key is checked
+        let file = BackPack::create_encrypted(RawFile::in_memory("test.bp"), key)?.close()?;
+        assert!(matches!(BackPack::open_encrypted(file, [8; 32]), Err(PackError::WrongKey)));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async() -> Result<(), PackError> {
+        use futures::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("backpack_test_async_{}.bp", std::process::id()));
+        let bp = BackPack::create(RawFile::create(&path)?)?;
+        bp.add_file_named("hello", "test.txt")?;
+        bp.close()?;
+
+        futures::executor::block_on(async {
+            let backing = std::fs::OpenOptions::new().read(true).write(true).open(&path)?;
+            let mut bp = BackPack::open_async(RawFile::from(backing)).await?;
+
+            let mut f = bp.get_file("test.txt")?;
+            AsyncSeekExt::seek(&mut f, SeekFrom::End(0)).await?;
+            AsyncWriteExt::write_all(&mut f, b" world").await?;
+            AsyncSeekExt::seek(&mut f, SeekFrom::Start(0)).await?;
+            let mut contents = String::new();
+            AsyncReadExt::read_to_string(&mut f, &mut contents).await?;
+            assert_eq!(contents, "hello world");
+            drop(f);
+
+            bp.flush_async().await?;
+            bp.close_async().await?;
+            Ok::<_, PackError>(())
+        })?;
+
+        let bp = BackPack::open_mmap(&path)?;
+        assert_eq!(&*bp.get_file("test.txt")?.try_get_bytes()?, b"hello world");
+        bp.close()?;
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,47 @@
+use std::path::Path;
+use crate::error;
+use crate::error::PackError;
+
+/// Normalise the name of a file or directory in a pack. Components are separated
+/// by `/` (`\` is accepted as well), and `.` and `..` components are resolved, so
+/// `./a/b.txt` and `a\b.txt` both name `a/b.txt`. The root of the pack is named by
+/// the empty string. Absolute names, and names escaping the root with `..`, are rejected.
+pub(crate) fn normalize(path: &Path) -> error::Result<String> {
+    let name = path.to_string_lossy();
+    let invalid = || PackError::InvalidName(path.to_path_buf());
+
+    if path.has_root() || name.starts_with(['/', '\\']) || has_drive_prefix(&name) {
+        return Err(invalid());
+    }
+
+    let mut components = Vec::new();
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop().ok_or_else(invalid)?;
+            }
+            component => components.push(component),
+        }
+    }
+
+    Ok(components.join("/"))
+}
+
+/// Same as [`normalize`], but for files and directories which can't be the root.
+pub(crate) fn normalize_entry(path: &Path) -> error::Result<String> {
+    match normalize(path)? {
+        name if name.is_empty() => Err(PackError::InvalidName(path.to_path_buf())),
+        name => Ok(name),
+    }
+}
+
+/// Windows paths like `C:\a` or `C:a`, which are relative on other platforms
+fn has_drive_prefix(name: &str) -> bool {
+    matches!(name.as_bytes(), [drive, b':', ..] if drive.is_ascii_alphabetic())
+}
+
+/// All directories containing `name`, from the outermost to the innermost
+pub(crate) fn ancestors(name: &str) -> impl Iterator<Item=&str> {
+    name.match_indices('/').map(move |(i, _)| &name[..i])
+}
@@ -1,13 +1,20 @@
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::ops::{Deref, DerefMut};
+use std::ops::Deref;
+use std::sync::Arc;
 use parking_lot::RwLock;
-use crate::BackPack;
+use crate::{error, BackPack};
+use crate::pack::maybe_ref::MaybeRef;
 
 pub struct PackSlice<'f, 'backpack> {
+    /// name of the file in the pack, which is changed to refer
+    /// to a copy when shared contents are written to.
+    name: String,
     start: u64,
     end: u64,
 
     pos: u64,
+    /// contents in parsed packs, which stay usable when the file is removed
+    blob: Option<Arc<RwLock<Vec<u8>>>>,
 
     pub(crate) pack: &'f BackPack<'f, 'backpack>
 }
@@ -15,24 +22,33 @@ pub struct PackSlice<'f, 'backpack> {
 impl Clone for PackSlice<'_, '_> {
     fn clone(&self) -> Self {
         PackSlice {
+            name: self.name.clone(),
             start: self.start,
             end: self.end,
             pos: self.pos,
+            blob: self.blob.clone(),
             pack: self.pack
         }
     }
 }
 
 impl<'f, 'backpack> PackSlice<'f, 'backpack> {
-    pub fn new(start: u64, end: u64, pack: &'f BackPack<'f, 'backpack>) -> Self {
+    pub fn new(name: impl Into<String>, start: u64, end: u64, pack: &'f BackPack<'f, 'backpack>) -> Self {
         Self {
+            name: name.into(),
             start,
             end,
             pos: 0,
+            blob: pack.pin((start, end)),
             pack
         }
     }
 
+    /// Name of the file in the pack
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn position(&self) -> u64 {
         self.pos
     }
@@ -41,19 +57,33 @@ impl<'f, 'backpack> PackSlice<'f, 'backpack> {
         (self.start, self.end)
     }
 
-    pub fn get_bytes(&self) -> &RwLock<Vec<u8>> {
-        self.pack.retrieve_slice(self)
+    pub fn get_bytes(&self) -> error::Result<MaybeRef<'f, [u8]>> {
+        match &self.blob {
+            Some(blob) => Ok(RwLock::read_arc(blob).into()),
+            None => self.pack.retrieve_bytes(self.identifier()),
+        }
+    }
+
+    pub fn resize(&mut self, size: u64) -> error::Result<()> {
+        self.modify(|v| v.resize(size as usize, 0))
     }
 
-    pub fn resize(&mut self, size: u64) {
-        let v = self.pack.retrieve_slice(self);
-        v.write().resize(size as usize, 0);
+    /// Change the contents of the slice. Shared contents are copied
+    /// first (see [`BackPack::modify`]), after which this slice refers to the copy.
+    fn modify<T>(&mut self, f: impl FnOnce(&mut Vec<u8>) -> T) -> error::Result<T> {
+        let ((start, end), res) = self.pack.modify(&self.name, self.identifier(), self.blob.as_ref(), f)?;
+        if (start, end) != self.identifier() {
+            self.blob = self.pack.pin((start, end));
+        }
+        self.start = start;
+        self.end = end;
+        Ok(res)
     }
 }
 
 impl Read for PackSlice<'_, '_> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let g = self.pack.retrieve_slice(self).read();
+        let g = self.get_bytes().map_err(Into::<std::io::Error>::into)?;
 
         let mut c = Cursor::new(g.deref());
         c.set_position(self.pos);
@@ -66,14 +96,13 @@ impl Read for PackSlice<'_, '_> {
 
 impl Write for PackSlice<'_, '_> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut g = self.pack
-            .retrieve_slice(self)
-            .write();
-
-        let mut c = Cursor::new(g.deref_mut());
-        c.set_position(self.pos);
-        let res = c.write(buf)?;
-        self.pos = c.position();
+        let pos = self.pos;
+        let (res, pos) = self.modify(|v| {
+            let mut c = Cursor::new(v);
+            c.set_position(pos);
+            c.write(buf).map(|res| (res, c.position()))
+        }).map_err(Into::<std::io::Error>::into)??;
+        self.pos = pos;
 
         Ok(res)
     }
@@ -85,10 +114,7 @@ impl Write for PackSlice<'_, '_> {
 
 impl Seek for PackSlice<'_, '_> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        let g = self.pack
-            .retrieve_slice(self)
-            .read();
-
+        let g = self.get_bytes().map_err(Into::<std::io::Error>::into)?;
 
         let mut c = Cursor::new(g.deref());
         c.set_position(self.pos);
@@ -98,4 +124,4 @@ impl Seek for PackSlice<'_, '_> {
 
         Ok(res)
     }
-}
\ No newline at end of file
+}